@@ -1,6 +1,30 @@
 pub mod regex;
 
-#[derive(Debug, PartialEq)]
+/// Asserts that a regex matches all of the input, panics with the regex,
+/// the input and the NFA's size if it doesn't or if the regex won't compile
+#[macro_export]
+macro_rules! assert_matches_regex {
+    ($regex:expr, $input:expr) => {{
+        let regex: &str = $regex;
+        let input: &[u8] = $input.as_ref();
+        match $crate::regex::get_nfa(regex) {
+            Ok(nfa) => {
+                if !$crate::regex::simulate::matches(&nfa, input) {
+                    panic!(
+                        "regex {:?} does not match {:?} (NFA has {} nodes)",
+                        regex,
+                        String::from_utf8_lossy(input),
+                        nfa.len()
+                    );
+                }
+            }
+            Err(error) => panic!("regex {:?} failed to compile: {}", regex, error),
+        }
+    }};
+}
+
+/// Errors only carry a message, so two errors are equal when their messages are
+#[derive(Debug, PartialEq, Eq)]
 pub struct Error {
     message: String,
 }
@@ -34,3 +58,36 @@ impl std::error::Error for Error {
         None
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn equality() {
+        assert_eq!(Error::new("Mismatched []"), Error::new("Mismatched []"));
+        assert_eq!(
+            *Error::new_box("Mismatched []"),
+            Error::new("Mismatched []")
+        );
+        assert_ne!(Error::new("Mismatched []"), Error::new("Mismatched {}"));
+    }
+
+    #[test]
+    fn matches_macro() {
+        assert_matches_regex!("a(b|c)*", "abcb");
+        assert_matches_regex!("[^a]", b"b");
+    }
+
+    #[test]
+    #[should_panic(expected = "regex \"a(b|c)*\" does not match \"ad\" (NFA has")]
+    fn matches_macro_fails() {
+        assert_matches_regex!("a(b|c)*", "ad");
+    }
+
+    #[test]
+    #[should_panic(expected = "failed to compile")]
+    fn matches_macro_bad_regex() {
+        assert_matches_regex!("a{0}", "");
+    }
+}
@@ -1,7 +1,13 @@
+pub mod byteset;
+#[cfg(test)]
+mod monkey;
 pub mod nfa;
 pub mod parse;
+pub mod regex_set;
 pub mod scan;
+pub mod serialize;
 pub mod simplify;
+pub mod simulate;
 
 use crate::Error;
 use parse::UnaryOperation;
@@ -15,53 +21,207 @@ pub fn get_rast(regex: &str) -> Result<parse::RAST, Error> {
     Ok(*rast)
 }
 
+/// Settings used when compiling a regex
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    /// Compiling fails once the NFA grows past this many nodes
+    pub max_states: usize,
+    /// Whether [^...] sets can match \n, they do by default like POSIX
+    pub negated_sets_match_newline: bool,
+    /// Makes unknown escapes like \q an error instead of a literal q
+    pub strict_escapes: bool,
+    /// Highest byte . and [^...] can match, the default keeps them to ASCII
+    pub max_byte: u8,
+    /// Lets an alternation branch be empty, so (a|)b means a?b instead of an error
+    pub allow_empty_alternation: bool,
+    /// Same as starting the regex with (?i)
+    pub case_insensitive: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            max_states: 100_000,
+            negated_sets_match_newline: true,
+            strict_escapes: false,
+            max_byte: 127,
+            allow_empty_alternation: false,
+            case_insensitive: false,
+        }
+    }
+}
+
+/// Sets up Options one setting at a time and compiles regexes with them
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RegexBuilder {
+    options: Options,
+}
+
+impl RegexBuilder {
+    pub fn new() -> RegexBuilder {
+        RegexBuilder {
+            options: Options::default(),
+        }
+    }
+
+    pub fn max_states(&mut self, max_states: usize) -> &mut RegexBuilder {
+        self.options.max_states = max_states;
+        self
+    }
+
+    pub fn negated_sets_match_newline(&mut self, yes: bool) -> &mut RegexBuilder {
+        self.options.negated_sets_match_newline = yes;
+        self
+    }
+
+    pub fn strict_escapes(&mut self, yes: bool) -> &mut RegexBuilder {
+        self.options.strict_escapes = yes;
+        self
+    }
+
+    pub fn max_byte(&mut self, max_byte: u8) -> &mut RegexBuilder {
+        self.options.max_byte = max_byte;
+        self
+    }
+
+    pub fn allow_empty_alternation(&mut self, yes: bool) -> &mut RegexBuilder {
+        self.options.allow_empty_alternation = yes;
+        self
+    }
+
+    pub fn case_insensitive(&mut self, yes: bool) -> &mut RegexBuilder {
+        self.options.case_insensitive = yes;
+        self
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    pub fn build(&self, regex: &str) -> Result<nfa::NFA, Error> {
+        get_nfa_opts(regex, &self.options)
+    }
+}
+
 pub fn get_nfa(regex: &str) -> Result<nfa::NFA, Error> {
-    let tokens = scan::scan(regex)?;
-    let simple = simplify::simpilfy(&tokens[..])?;
+    get_nfa_opts(regex, &Options::default())
+}
+
+pub fn get_nfa_opts(regex: &str, options: &Options) -> Result<nfa::NFA, Error> {
+    let tokens = scan::scan_opts(regex, options)?;
+    let simple = simplify::simpilfy_opts(&tokens[..], options)?;
     let rast = parse::parse(&simple[..])?;
     check_rast(&rast)?;
-    Ok(nfa::rast_to_nfa(&rast))
+    nfa::rast_to_nfa_limited(&rast, options.max_states)
+}
+
+/// NFA accepting exactly the reversals of the strings regex accepts
+pub fn compile_reverse(regex: &str) -> Result<nfa::NFA, Error> {
+    let rast = parse::reverse(get_rast(regex)?);
+    nfa::rast_to_nfa_limited(&rast, Options::default().max_states)
+}
+
+/// Compiles every regex, or gives the index and error of the first one that fails
+pub fn compile_many(regexes: &[&str]) -> Result<Vec<nfa::NFA>, (usize, Error)> {
+    regexes
+        .iter()
+        .enumerate()
+        .map(|(index, regex)| get_nfa(regex).map_err(|error| (index, error)))
+        .collect()
+}
+
+/// Checks whether a whole input matches
+pub type Matcher = Box<dyn Fn(&[u8]) -> bool>;
+
+/// Compiles the regex once and returns a closure that checks full matches against it
+pub fn compile_matcher(regex: &str) -> Result<Matcher, Error> {
+    let nfa = get_nfa(regex)?;
+    Ok(Box::new(move |input| simulate::matches(&nfa, input)))
+}
+
+/// Backslash escapes every metacharacter so the result matches text literally
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '.' | '*' | '+' | '?' | '(' | ')' | '[' | ']' | '{' | '}' | '|' | '\\' | '^' | '$' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
 }
 
-enum RegexType {
+/// The kind of operation at the top of a regex
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RegexType {
     Binary,
     Unary,
     Atomic,
 }
 
+pub fn classify(regex: &str) -> Result<RegexType, Error> {
+    check_rast(&get_rast(regex)?)
+}
+
+/// Walks the RAST with its own stack so deep regexes can't overflow it.
+/// Quantifiers are checked on the way down and two quantifiers in a row on
+/// the way back up, so errors come out in the same order a recursive walk
+/// would find them
 fn check_rast(regex: &RAST) -> Result<RegexType, Error> {
-    match regex {
-        RAST::Binary(left, right, _) => {
-            check_rast(&left)?;
-            check_rast(&right)?;
-            Ok(RegexType::Binary)
-        }
-        RAST::Unary(left, op) => {
-            match op {
-                UnaryOperation::MinMax(min, max) => {
-                    if min >= max {
-                        return Err(Error::new(
-                            "In {min,max} operator, min should be less than max",
-                        ));
-                    }
-                }
-                UnaryOperation::Times(times) => {
-                    if *times == 0 {
-                        return Err(Error::new(
-                            "In {times} operator, times should be greater than zero",
-                        ));
-                    }
+    let mut stack = vec![(regex, false)];
+    while let Some((node, children_checked)) = stack.pop() {
+        match node {
+            RAST::Binary(left, right, _) => {
+                stack.push((right, false));
+                stack.push((left, false));
+            }
+            RAST::Unary(inner, _) if children_checked => {
+                if let RAST::Unary(_, _) = **inner {
+                    return Err(Error::new("Cannot have two unary operations in a row"));
                 }
-                _ => (),
             }
-            let left = check_rast(&left)?;
-            match left {
-                RegexType::Unary => Err(Error::new("Cannot have two unary operations in a row")),
-                _ => Ok(RegexType::Unary),
+            RAST::Unary(inner, op) => {
+                check_unary(*op)?;
+                stack.push((node, true));
+                stack.push((inner, false));
             }
+            RAST::Atomic(_) | RAST::Set(_) | RAST::Empty => (),
         }
-        RAST::Atomic(_) => Ok(RegexType::Atomic),
     }
+    Ok(match regex {
+        RAST::Binary(_, _, _) => RegexType::Binary,
+        RAST::Unary(_, _) => RegexType::Unary,
+        RAST::Atomic(_) | RAST::Set(_) | RAST::Empty => RegexType::Atomic,
+    })
+}
+
+fn check_unary(op: UnaryOperation) -> Result<(), Error> {
+    match op {
+        UnaryOperation::MinMax(min, max) => {
+            if min > max {
+                return Err(Error::new(
+                    "In {min,max} operator, min should not be greater than max",
+                ));
+            }
+            if max == 0 {
+                return Err(Error::new(
+                    "In {min,max} operator, max should be greater than zero",
+                ));
+            }
+        }
+        UnaryOperation::Times(times) => {
+            if times == 0 {
+                return Err(Error::new(
+                    "In {times} operator, times should be greater than zero",
+                ));
+            }
+        }
+        _ => (),
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -70,7 +230,7 @@ mod test {
 
     #[test]
     fn adj_unary() {
-        let regex = "a*+";
+        let regex = "a+?";
         let regex = crate::regex::get_rast(regex);
         assert_eq!(
             regex,
@@ -85,6 +245,163 @@ mod test {
         );
     }
 
+    #[test]
+    fn equal_min_max() -> Result<(), Error> {
+        let regex = "a{2,2}";
+        assert_eq!(get_rast(regex)?, get_rast("a{2}")?);
+        assert_eq!(get_nfa(regex)?, get_nfa("aa")?);
+
+        let regex = "a{3,2}";
+        assert_eq!(
+            get_rast(regex),
+            Err(Error::new(
+                "In {min,max} operator, min should not be greater than max"
+            ))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn classifying() -> Result<(), Error> {
+        assert_eq!(classify("a")?, RegexType::Atomic);
+        assert_eq!(classify("(a)")?, RegexType::Atomic);
+        assert_eq!(classify("a*")?, RegexType::Unary);
+        assert_eq!(classify("ab")?, RegexType::Binary);
+        assert_eq!(classify("a|b*")?, RegexType::Binary);
+        assert_eq!(
+            classify("(a*)+"),
+            Err(Error::new("Cannot have two unary operations in a row"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn open_min_max() -> Result<(), Error> {
+        assert_eq!(
+            get_rast("a{,0}"),
+            Err(Error::new(
+                "In {min,max} operator, max should be greater than zero"
+            ))
+        );
+        assert_eq!(
+            get_rast("a{0,0}"),
+            Err(Error::new(
+                "In {min,max} operator, max should be greater than zero"
+            ))
+        );
+        assert_eq!(get_rast("a{,2}")?, get_rast("a{0,2}")?);
+
+        let nfa = get_nfa("a{5,}")?;
+        assert!(!simulate::matches(&nfa, b"aaaa"));
+        assert!(simulate::matches(&nfa, b"aaaaa"));
+        assert!(simulate::matches(&nfa, b"aaaaaaaa"));
+        Ok(())
+    }
+
+    #[test]
+    fn escaping() -> Result<(), Error> {
+        assert_eq!(escape("a.b*"), "a\\.b\\*");
+
+        let text = r"a.b*c+d?(e)[f]{g}h|i\j^k$";
+        let tokens = scan::scan(&escape(text))?;
+        let expected: Vec<scan::FirstRegexToken> =
+            text.bytes().map(scan::FirstRegexToken::Character).collect();
+        assert_eq!(tokens, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn empty_alternation() -> Result<(), Error> {
+        let options = Options {
+            allow_empty_alternation: true,
+            ..Options::default()
+        };
+        let nfa = get_nfa_opts("(a|)b", &options)?;
+        assert!(simulate::matches(&nfa, b"ab"));
+        assert!(simulate::matches(&nfa, b"b"));
+        assert!(!simulate::matches(&nfa, b"a"));
+
+        let nfa = get_nfa_opts("|a||b", &options)?;
+        for input in [&b""[..], b"a", b"b"].iter() {
+            assert!(simulate::matches(&nfa, input));
+        }
+        assert!(get_nfa("(a|)b").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn compiling_many() -> Result<(), Error> {
+        let nfas = compile_many(&["a", "b*"]).map_err(|(_, error)| error)?;
+        assert_eq!(nfas, [get_nfa("a")?, get_nfa("b*")?]);
+
+        assert_eq!(
+            compile_many(&["a", "b{0}", "c", "("]),
+            Err((
+                1,
+                Error::new("In {times} operator, times should be greater than zero")
+            ))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn builder() -> Result<(), Error> {
+        let nfa = RegexBuilder::new()
+            .case_insensitive(true)
+            .allow_empty_alternation(true)
+            .build("(a|)b")?;
+        for input in [&b"ab"[..], b"AB", b"b", b"B"].iter() {
+            assert!(simulate::matches(&nfa, input));
+        }
+
+        let mut builder = RegexBuilder::new();
+        builder.max_byte(255).negated_sets_match_newline(false);
+        let nfa = builder.build("[^a]")?;
+        assert!(simulate::matches(&nfa, &[0xff]));
+        assert!(!simulate::matches(&nfa, b"\n"));
+
+        builder.strict_escapes(true).max_states(2);
+        assert_eq!(
+            builder.build(r"\q"),
+            Err(Error::new("Unknown escape sequence \\q at position 0"))
+        );
+        assert_eq!(
+            builder.build("ab"),
+            Err(Error::new("NFA exceeded max_states"))
+        );
+        assert_eq!(RegexBuilder::new().options(), &Options::default());
+        Ok(())
+    }
+
+    #[test]
+    fn reversed() -> Result<(), Error> {
+        for regex in ["abc", "a(b|c)d", "(ab)*c{2,3}"].iter() {
+            let forward = get_nfa(regex)?;
+            let backward = compile_reverse(regex)?;
+            for input in simulate::sample_strings(&forward, 20) {
+                let reversed: Vec<u8> = input.iter().rev().cloned().collect();
+                assert!(simulate::matches(&backward, &reversed));
+            }
+        }
+        let backward = compile_reverse("abc")?;
+        assert!(simulate::matches(&backward, b"cba"));
+        assert!(!simulate::matches(&backward, b"abc"));
+        Ok(())
+    }
+
+    #[test]
+    fn matcher() -> Result<(), Error> {
+        let regex = "a(b|c)*d?";
+        let matcher = compile_matcher(regex)?;
+        let nfa = get_nfa(regex)?;
+        let inputs: [&[u8]; 7] = [b"", b"a", b"abc", b"abcd", b"ad", b"da", b"abdc"];
+        for input in inputs.iter() {
+            assert_eq!(matcher(input), simulate::matches(&nfa, input));
+        }
+        assert!(compile_matcher("a{2,1}").is_err());
+        Ok(())
+    }
+
     #[test]
     fn bad_times_min_max() {
         let regex = "a{2,1}";
@@ -92,7 +409,7 @@ mod test {
         assert_eq!(
             regex,
             Err(Error::new(
-                "In {min,max} operator, min should be less than max"
+                "In {min,max} operator, min should not be greater than max"
             ))
         );
 
@@ -0,0 +1,84 @@
+/// A set of bytes stored as a 256 bit bitmap
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ByteSet([u64; 4]);
+
+impl ByteSet {
+    pub fn new() -> ByteSet {
+        ByteSet([0; 4])
+    }
+
+    pub fn insert(&mut self, byte: u8) {
+        self.0[(byte >> 6) as usize] |= 1 << (byte & 63);
+    }
+
+    pub fn contains(&self, byte: u8) -> bool {
+        self.0[(byte >> 6) as usize] & (1 << (byte & 63)) != 0
+    }
+
+    pub fn union(&self, other: &ByteSet) -> ByteSet {
+        let mut set = *self;
+        for (word, other) in set.0.iter_mut().zip(other.0.iter()) {
+            *word |= other;
+        }
+        set
+    }
+
+    /// Every byte in 0..=255 that isn't in this set
+    pub fn invert(&self) -> ByteSet {
+        let mut set = *self;
+        for word in &mut set.0 {
+            *word = !*word;
+        }
+        set
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|word| *word == 0)
+    }
+
+    /// Iterates over the bytes in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=255).filter(move |byte| self.contains(*byte))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn insert_contains() {
+        let mut set = ByteSet::new();
+        assert!(set.is_empty());
+        set.insert(0);
+        set.insert(b'a');
+        set.insert(255);
+        assert!(set.contains(0));
+        assert!(set.contains(b'a'));
+        assert!(set.contains(255));
+        assert!(!set.contains(b'b'));
+        assert_eq!(set.len(), 3);
+        assert_eq!(set.iter().collect::<Vec<u8>>(), vec![0, b'a', 255]);
+    }
+
+    #[test]
+    fn union_invert() {
+        let mut first = ByteSet::new();
+        first.insert(b'a');
+        let mut second = ByteSet::new();
+        second.insert(b'b');
+        let both = first.union(&second);
+        assert_eq!(both.iter().collect::<Vec<u8>>(), vec![b'a', b'b']);
+
+        let inverse = both.invert();
+        assert_eq!(inverse.len(), 254);
+        assert!(!inverse.contains(b'a'));
+        assert!(!inverse.contains(b'b'));
+        assert!(inverse.contains(b'c'));
+        assert_eq!(inverse.invert(), both);
+    }
+}
@@ -0,0 +1,61 @@
+//! Shared driver for the monkey tests, set MONKEY_SEED to replay a failing run
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::panic::{self, AssertUnwindSafe};
+
+/// Runs check on random printable patterns, panics with the seed and the
+/// smallest pattern that still fails if check ever panics
+pub fn run<F: Fn(&str)>(iterations: usize, check: F) {
+    let seed = match std::env::var("MONKEY_SEED") {
+        Ok(seed) => seed.parse().expect("MONKEY_SEED should be a number"),
+        Err(_) => rand::thread_rng().gen_range(0, u64::MAX),
+    };
+    let mut rng = StdRng::seed_from_u64(seed);
+    for _ in 0..iterations {
+        let length = rng.gen_range(0, 16);
+        let mut regex = String::new();
+        for _ in 0..length {
+            regex.push(rng.gen_range(32, 127) as u8 as char);
+        }
+        if fails(&check, &regex) {
+            panic!(
+                "monkey failed on {:?} (shrunk from {:?}), rerun with MONKEY_SEED={}",
+                shrink(&check, regex.clone()),
+                regex,
+                seed
+            );
+        }
+    }
+}
+
+fn fails<F: Fn(&str)>(check: &F, regex: &str) -> bool {
+    panic::catch_unwind(AssertUnwindSafe(|| check(regex))).is_err()
+}
+
+/// Removes one character at a time for as long as the pattern keeps failing
+fn shrink<F: Fn(&str)>(check: &F, mut regex: String) -> String {
+    let mut index = 0;
+    while index < regex.len() {
+        let mut smaller = regex.clone();
+        smaller.remove(index);
+        if fails(check, &smaller) {
+            regex = smaller;
+        } else {
+            index += 1;
+        }
+    }
+    regex
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shrinking() {
+        let check = |regex: &str| assert!(!regex.contains('*'));
+        assert_eq!(shrink(&check, "ab*(c".to_string()), "*");
+        assert!(fails(&check, "a*"));
+        assert!(!fails(&check, "a+"));
+    }
+}
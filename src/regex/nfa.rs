@@ -1,21 +1,42 @@
+use super::byteset::ByteSet;
 use super::parse::BinaryOperation;
 use super::parse::UnaryOperation;
 use super::parse::RAST;
+use super::simulate::{epsilon_closure, step};
+use crate::Error;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
 use BinaryOperation::*;
 use Transition::*;
 use UnaryOperation::*;
 use RAST::*;
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Transition {
     Epsilon(Vec<usize>),
     Character(u8, usize),
+    Set(ByteSet, usize),
 }
 
 // first element is the start node
 // last element is the finish node
 pub type NFA = Vec<Transition>;
 
+/// Lets `for (node, transition) in nfa.iter_transitions()` walk an NFA
+pub trait IterTransitions {
+    fn iter_transitions(&self) -> std::iter::Enumerate<std::slice::Iter<'_, Transition>>;
+}
+
+impl IterTransitions for NFA {
+    fn iter_transitions(&self) -> std::iter::Enumerate<std::slice::Iter<'_, Transition>> {
+        self.iter().enumerate()
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 struct Range {
     start: usize,
@@ -31,88 +52,555 @@ impl Transition {
     }
 }
 
-fn new_epsilon(nfa: &mut NFA, transitions: Vec<usize>) -> usize {
-    nfa.push(Epsilon(transitions));
-    nfa.len() - 1
+/// Adds nodes to the end of an NFA by hand, each returns the new node's index
+pub trait PushTransitions {
+    fn push_char(&mut self, byte: u8, to: usize) -> usize;
+    fn push_set(&mut self, set: ByteSet, to: usize) -> usize;
+    fn push_epsilon(&mut self, to: Vec<usize>) -> usize;
+}
+
+impl PushTransitions for NFA {
+    fn push_char(&mut self, byte: u8, to: usize) -> usize {
+        self.push(Character(byte, to));
+        self.len() - 1
+    }
+
+    fn push_set(&mut self, set: ByteSet, to: usize) -> usize {
+        self.push(Transition::Set(set, to));
+        self.len() - 1
+    }
+
+    fn push_epsilon(&mut self, to: Vec<usize>) -> usize {
+        self.push(Epsilon(to));
+        self.len() - 1
+    }
 }
 
 fn add_nfa(nfa: &mut NFA, mut to_insert: NFA) -> Range {
-    for transition in &mut to_insert {
+    shift(&mut to_insert, nfa.len());
+    let start = nfa.len();
+    nfa.append(&mut to_insert);
+    Range {
+        start,
+        end: nfa.len() - 1,
+    }
+}
+
+/// Copy of nfa with every transition target shifted by offset
+pub fn remap(nfa: &NFA, offset: usize) -> NFA {
+    let mut nfa = nfa.clone();
+    shift(&mut nfa, offset);
+    nfa
+}
+
+fn shift(nfa: &mut NFA, offset: usize) {
+    for transition in nfa {
         match transition {
             Epsilon(to) => {
                 for pos in to {
-                    *pos += nfa.len();
+                    *pos += offset;
                 }
             }
-            Character(_, to) => *to += nfa.len(),
+            Character(_, to) => *to += offset,
+            Transition::Set(_, to) => *to += offset,
         }
     }
-    let start = nfa.len();
-    nfa.append(&mut to_insert);
-    Range {
-        start,
-        end: nfa.len() - 1,
-    }
 }
 
 pub fn rast_to_nfa(rast: &RAST) -> NFA {
-    match rast {
-        Atomic(atomic) => vec![Character(*atomic, 1), Epsilon(Vec::new())],
-        Binary(left, right, op) => construct_binary_op(left, right, *op),
-        Unary(rast, op) => construct_unary_op(rast, *op),
+    rast_to_nfa_limited(rast, usize::MAX).expect("NFA can't have more than usize::MAX states")
+}
+
+/// Same as rast_to_nfa but gives up once the NFA has more than max_states nodes
+pub fn rast_to_nfa_limited(rast: &RAST, max_states: usize) -> Result<NFA, Error> {
+    let nfa = construct_nfa(rast, max_states)?;
+    debug_assert_eq!(validate(&nfa), Ok(()));
+    Ok(nfa)
+}
+
+/// Hash of the NFA's transitions, identical NFAs always hash the same
+pub fn structural_hash(nfa: &NFA) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    nfa.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Nodes the NFA accepts in, the finish node and every node that reaches it
+/// through epsilons alone, sorted. For a union of NFAs whose ends all lead to
+/// a shared finish node this reports each branch end
+pub fn accepting_states(nfa: &NFA) -> Vec<usize> {
+    if nfa.is_empty() {
+        return Vec::new();
+    }
+    let mut epsilon_from: Vec<Vec<usize>> = vec![Vec::new(); nfa.len()];
+    for (from, transition) in nfa.iter().enumerate() {
+        if let Epsilon(to) = transition {
+            for node in to {
+                epsilon_from[*node].push(from);
+            }
+        }
+    }
+    let mut accepting = vec![false; nfa.len()];
+    let mut stack = vec![nfa.len() - 1];
+    while let Some(node) = stack.pop() {
+        if !accepting[node] {
+            accepting[node] = true;
+            stack.extend(epsilon_from[node].iter().cloned());
+        }
     }
+    (0..nfa.len()).filter(|node| accepting[*node]).collect()
 }
 
-fn construct_binary_op(left: &RAST, right: &RAST, op: BinaryOperation) -> NFA {
-    let mut nfa = Vec::new();
+/// Merges nodes with exactly the same transitions until nothing changes,
+/// the start stays first and the finish stays last
+pub fn dedup_states(nfa: &NFA) -> NFA {
+    let mut nfa = nfa.clone();
+    if nfa.is_empty() {
+        return nfa;
+    }
+    loop {
+        let finish = nfa.len() - 1;
+        let mut first_with: HashMap<&Transition, usize> = HashMap::new();
+        let mut merged_into: Vec<usize> = (0..nfa.len()).collect();
+        for (node, transition) in nfa.iter().enumerate() {
+            if node != finish {
+                merged_into[node] = *first_with.entry(transition).or_insert(node);
+            }
+        }
+        if merged_into
+            .iter()
+            .enumerate()
+            .all(|(node, into)| node == *into)
+        {
+            return nfa;
+        }
 
-    match op {
-        Concat => {
-            let left = add_nfa(&mut nfa, rast_to_nfa(left));
-            let right = add_nfa(&mut nfa, rast_to_nfa(right));
-            nfa[left.end].add_epsilon(right.start);
-        }
-        Alternation => {
-            let start = new_epsilon(&mut nfa, Vec::new());
-            let left = add_nfa(&mut nfa, rast_to_nfa(left));
-            let right = add_nfa(&mut nfa, rast_to_nfa(right));
-            let end = new_epsilon(&mut nfa, Vec::new());
-            nfa[start].add_epsilon(left.start);
-            nfa[start].add_epsilon(right.start);
-            nfa[left.end].add_epsilon(end);
-            nfa[right.end].add_epsilon(end);
+        let mut renumber = vec![0; nfa.len()];
+        let mut kept = 0;
+        for node in 0..nfa.len() {
+            if merged_into[node] == node {
+                renumber[node] = kept;
+                kept += 1;
+            }
+        }
+        let target = |to: usize| renumber[merged_into[to]];
+        nfa = nfa
+            .iter()
+            .enumerate()
+            .filter(|(node, _)| merged_into[*node] == *node)
+            .map(|(_, transition)| match transition {
+                Epsilon(to) => {
+                    let mut targets: Vec<usize> = Vec::new();
+                    for next in to.iter().map(|to| target(*to)) {
+                        if !targets.contains(&next) {
+                            targets.push(next);
+                        }
+                    }
+                    Epsilon(targets)
+                }
+                Character(c, to) => Character(*c, target(*to)),
+                Transition::Set(set, to) => Transition::Set(*set, target(*to)),
+            })
+            .collect();
+    }
+}
+
+/// Renumbers nodes in breadth first order from the start so NFAs that only
+/// differ in how their nodes are numbered compare equal. Epsilon targets are
+/// visited ordered by their signature, the finish node stays last and nodes
+/// that can't be reached are dropped
+pub fn canonical(nfa: &NFA) -> NFA {
+    if nfa.is_empty() {
+        return NFA::new();
+    }
+    let finish = nfa.len() - 1;
+    let signature = signatures(nfa);
+    let mut labels: Vec<Option<usize>> = vec![None; nfa.len()];
+    let mut order = Vec::new();
+    let mut queue = VecDeque::new();
+    queue.push_back(0);
+    let mut finish_reached = false;
+    while let Some(node) = queue.pop_front() {
+        if node == finish {
+            // the finish is labeled last but what comes after it still needs visiting
+            if finish_reached {
+                continue;
+            }
+            finish_reached = true;
+        } else if labels[node].is_some() {
+            continue;
+        } else {
+            labels[node] = Some(order.len());
+            order.push(node);
+        }
+        match &nfa[node] {
+            Epsilon(to) => {
+                let mut to = to.clone();
+                to.sort_by_key(|next| signature[*next]);
+                queue.extend(to);
+            }
+            Character(_, to) | Transition::Set(_, to) => queue.push_back(*to),
+        }
+    }
+    labels[finish] = Some(order.len());
+    order.push(finish);
+
+    let label = |node: &usize| labels[*node].expect("targets of reached nodes are reached");
+    order
+        .iter()
+        .map(|node| match &nfa[*node] {
+            Epsilon(to) => {
+                let mut to: Vec<usize> = to.iter().map(label).collect();
+                to.sort_unstable();
+                Epsilon(to)
+            }
+            Character(c, to) => Character(*c, label(to)),
+            Transition::Set(set, to) => Transition::Set(*set, label(to)),
+        })
+        .collect()
+}
+
+/// Gives every node a number that only depends on what it reads and the
+/// signatures of the nodes after it, not on how the nodes are numbered.
+/// Starts from what each node reads alone and splits nodes whose successors
+/// differ until nothing changes
+fn signatures(nfa: &NFA) -> Vec<usize> {
+    let finish = nfa.len() - 1;
+    let reads: Vec<(u8, Vec<u8>)> = nfa
+        .iter()
+        .enumerate()
+        .map(|(node, transition)| match transition {
+            _ if node == finish => (3, Vec::new()),
+            Epsilon(_) => (0, Vec::new()),
+            Character(c, _) => (1, vec![*c]),
+            Transition::Set(set, _) => (2, set.iter().collect()),
+        })
+        .collect();
+    let mut signature = number_sorted(&reads);
+    let mut count = signature.iter().max().map_or(0, |max| max + 1);
+    loop {
+        let keys: Vec<(usize, Vec<usize>)> = nfa
+            .iter()
+            .enumerate()
+            .map(|(node, transition)| {
+                let mut next: Vec<usize> = match transition {
+                    Epsilon(to) => to.iter().map(|to| signature[*to]).collect(),
+                    Character(_, to) | Transition::Set(_, to) => vec![signature[*to]],
+                };
+                next.sort_unstable();
+                (signature[node], next)
+            })
+            .collect();
+        let refined = number_sorted(&keys);
+        let refined_count = refined.iter().max().map_or(0, |max| max + 1);
+        if refined_count == count {
+            return signature;
         }
+        signature = refined;
+        count = refined_count;
+    }
+}
+
+/// Numbers each distinct key by where it falls in sorted order
+fn number_sorted<K: Ord + Clone>(keys: &[K]) -> Vec<usize> {
+    let mut numbers = BTreeMap::new();
+    for key in keys {
+        numbers.insert(key.clone(), 0);
+    }
+    for (number, value) in numbers.values_mut().enumerate() {
+        *value = number;
     }
+    keys.iter().map(|key| numbers[key]).collect()
+}
+
+/// Every byte some transition can consume, input with any other byte can't match
+pub fn used_bytes(nfa: &NFA) -> ByteSet {
+    let mut used = ByteSet::new();
+    for transition in nfa {
+        match transition {
+            Epsilon(_) => (),
+            Character(c, _) => used.insert(*c),
+            Transition::Set(set, _) => used = used.union(set),
+        }
+    }
+    used
+}
+
+/// The longest literal every accepted string has to contain, if there is one.
+/// A character node is required when the finish node can't be reached without it,
+/// required nodes are then chained while reading one leaves the next as the only
+/// choice
+pub fn required_literal(nfa: &NFA) -> Option<Vec<u8>> {
+    let required: Vec<usize> = (0..nfa.len())
+        .filter(|node| matches!(nfa[*node], Character(_, _)) && !reaches_finish_without(nfa, *node))
+        .collect();
+
+    let mut best: Option<Vec<u8>> = None;
+    for start in &required {
+        let mut literal = Vec::new();
+        let mut node = *start;
+        while let Character(c, to) = nfa[node] {
+            literal.push(c);
+            match only_next_character(nfa, to) {
+                Some(next) if required.contains(&next) && literal.len() < nfa.len() => node = next,
+                _ => break,
+            }
+        }
+        match &best {
+            Some(best) if best.len() >= literal.len() => (),
+            _ => best = Some(literal),
+        }
+    }
+    best
+}
+
+/// The bytes every accepted string starts with, empty if there is a choice
+/// right away. Stops as soon as the NFA could finish or read different bytes
+pub fn literal_prefix(nfa: &NFA) -> Vec<u8> {
+    let mut prefix = Vec::new();
+    if nfa.is_empty() {
+        return prefix;
+    }
+    let mut states = HashSet::new();
+    states.insert(0);
+    epsilon_closure(nfa, &mut states);
+    while !states.contains(&(nfa.len() - 1)) && prefix.len() < nfa.len() {
+        let mut next_byte = None;
+        for state in states.iter() {
+            match (&nfa[*state], next_byte) {
+                (Epsilon(_), _) => (),
+                (Character(c, _), None) => next_byte = Some(*c),
+                (Character(c, _), Some(byte)) if *c == byte => (),
+                _ => return prefix,
+            }
+        }
+        match next_byte {
+            Some(byte) => {
+                prefix.push(byte);
+                states = step(nfa, &states, byte);
+            }
+            None => break,
+        }
+    }
+    prefix
+}
+
+fn reaches_finish_without(nfa: &NFA, skip: usize) -> bool {
+    let mut seen = vec![false; nfa.len()];
+    let mut stack = vec![0];
+    while let Some(node) = stack.pop() {
+        if node == skip || seen[node] {
+            continue;
+        }
+        if node == nfa.len() - 1 {
+            return true;
+        }
+        seen[node] = true;
+        match &nfa[node] {
+            Epsilon(to) => stack.extend(to),
+            Character(_, to) | Transition::Set(_, to) => stack.push(*to),
+        }
+    }
+    false
+}
+
+/// The single node that can consume the next byte after reaching node,
+/// None if there is a choice or the NFA could finish there instead
+fn only_next_character(nfa: &NFA, node: usize) -> Option<usize> {
+    let mut states = HashSet::new();
+    states.insert(node);
+    epsilon_closure(nfa, &mut states);
+    if states.contains(&(nfa.len() - 1)) {
+        return None;
+    }
+    let mut consuming = states
+        .into_iter()
+        .filter(|state| !matches!(nfa[*state], Epsilon(_)));
+    match (consuming.next(), consuming.next()) {
+        (Some(next), None) => Some(next),
+        _ => None,
+    }
+}
+
+/// Checks the positional invariants every NFA built here should hold:
+/// every transition points at an existing node, there is a start and
+/// a separate finish node, and the finish node never consumes a character
+pub fn validate(nfa: &NFA) -> Result<(), Error> {
+    if nfa.len() < 2 {
+        return Err(Error::new(
+            "NFA must have at least a start and a finish node",
+        ));
+    }
+    for transition in nfa {
+        match transition {
+            Epsilon(to) => {
+                if to.iter().any(|&pos| pos >= nfa.len()) {
+                    return Err(Error::new(
+                        "NFA has a transition to a node that doesn't exist",
+                    ));
+                }
+            }
+            Character(_, to) | Transition::Set(_, to) => {
+                if *to >= nfa.len() {
+                    return Err(Error::new(
+                        "NFA has a transition to a node that doesn't exist",
+                    ));
+                }
+            }
+        }
+    }
+    if let Some(Character(_, _)) | Some(Transition::Set(_, _)) = nfa.last() {
+        return Err(Error::new(
+            "NFA finish node cannot have a character transition",
+        ));
+    }
+    Ok(())
+}
+
+fn check_states(nfa: &NFA, max_states: usize) -> Result<(), Error> {
+    if nfa.len() > max_states {
+        Err(Error::new("NFA exceeded max_states"))
+    } else {
+        Ok(())
+    }
+}
+
+enum Work<'a> {
+    Visit(&'a RAST),
+    Build(&'a RAST),
+}
+
+/// Builds the NFA bottom up with an explicit stack, so deep RASTs can't
+/// overflow the call stack. Children are always built before their parent
+/// and the left child before the right, which keeps the same layout a
+/// recursive construction would give
+fn construct_nfa(rast: &RAST, max_states: usize) -> Result<NFA, Error> {
+    let mut work = vec![Work::Visit(rast)];
+    let mut built: Vec<NFA> = Vec::new();
+    while let Some(next) = work.pop() {
+        match next {
+            Work::Visit(Atomic(atomic)) => {
+                built.push(vec![Character(*atomic, 1), Epsilon(Vec::new())]);
+                continue;
+            }
+            Work::Visit(RAST::Set(set)) => {
+                built.push(vec![Transition::Set(*set, 1), Epsilon(Vec::new())]);
+                continue;
+            }
+            Work::Visit(Empty) => {
+                built.push(vec![Epsilon(vec![1]), Epsilon(Vec::new())]);
+                continue;
+            }
+            Work::Visit(node @ Binary(left, right, _)) => {
+                work.push(Work::Build(node));
+                work.push(Work::Visit(right));
+                work.push(Work::Visit(left));
+                continue;
+            }
+            Work::Visit(node @ Unary(inner, _)) => {
+                work.push(Work::Build(node));
+                work.push(Work::Visit(inner));
+                continue;
+            }
+            Work::Build(Binary(_, _, op)) => {
+                let right = built.pop().expect("right side is built before binary");
+                let left = built.pop().expect("left side is built before binary");
+                built.push(construct_binary_op(left, right, *op));
+            }
+            Work::Build(Unary(_, op)) => {
+                let middle = built.pop().expect("middle is built before unary");
+                built.push(construct_unary_op(middle, *op, max_states)?);
+            }
+            Work::Build(Atomic(_)) | Work::Build(RAST::Set(_)) | Work::Build(Empty) => {
+                panic!("Programmer Error: Atomic, Set and Empty are never built")
+            }
+        }
+        check_states(built.last().expect("just built"), max_states)?;
+    }
+    Ok(built.pop().expect("root is built last"))
+}
+
+fn construct_binary_op(left: NFA, right: NFA, op: BinaryOperation) -> NFA {
+    match op {
+        Concat => concat(left, right),
+        Alternation => alternate(left, right),
+    }
+}
+
+/// NFA that accepts a string from a followed by a string from b
+pub fn concat_nfa(a: &NFA, b: &NFA) -> NFA {
+    concat(a.clone(), b.clone())
+}
+
+fn concat(left: NFA, right: NFA) -> NFA {
+    let mut nfa = Vec::new();
+    let left = add_nfa(&mut nfa, left);
+    let right = add_nfa(&mut nfa, right);
+    nfa[left.end].add_epsilon(right.start);
     nfa
 }
 
-fn construct_unary_op(rast: &RAST, op: UnaryOperation) -> NFA {
+/// NFA that accepts a string from any of the parts, with no parts nothing
+/// leads from the start to the finish so it accepts nothing
+pub fn alternate_nfa(parts: Vec<NFA>) -> NFA {
+    parts
+        .into_iter()
+        .rev()
+        .fold(None, |right, left| match right {
+            Some(right) => Some(alternate(left, right)),
+            None => Some(left),
+        })
+        .unwrap_or_else(|| vec![Epsilon(Vec::new()), Epsilon(Vec::new())])
+}
+
+fn alternate(left: NFA, right: NFA) -> NFA {
+    let mut nfa = Vec::new();
+    let start = nfa.push_epsilon(Vec::new());
+    let left = add_nfa(&mut nfa, left);
+    let right = add_nfa(&mut nfa, right);
+    let end = nfa.push_epsilon(Vec::new());
+    nfa[start].add_epsilon(left.start);
+    nfa[start].add_epsilon(right.start);
+    nfa[left.end].add_epsilon(end);
+    nfa[right.end].add_epsilon(end);
+    nfa
+}
+
+/// NFA that accepts zero or more strings from inner
+pub fn star_nfa(inner: &NFA) -> NFA {
+    kleen_closure(inner.clone())
+}
+
+fn kleen_closure(middle: NFA) -> NFA {
+    let mut nfa = Vec::new();
+    let start = nfa.push_epsilon(Vec::new());
+    let middle = add_nfa(&mut nfa, middle);
+    let end = nfa.push_epsilon(vec![start]);
+    nfa[start].add_epsilon(middle.start);
+    nfa[start].add_epsilon(end);
+    nfa[middle.end].add_epsilon(end);
+    nfa
+}
+
+fn construct_unary_op(middle: NFA, op: UnaryOperation, max_states: usize) -> Result<NFA, Error> {
     let mut nfa = Vec::new();
-    let middle = rast_to_nfa(rast);
 
     match op {
-        KleenClosure => {
-            let start = new_epsilon(&mut nfa, Vec::new());
-            let middle = add_nfa(&mut nfa, middle);
-            let end = new_epsilon(&mut nfa, vec![start]);
-            nfa[start].add_epsilon(middle.start);
-            nfa[start].add_epsilon(end);
-            nfa[middle.end].add_epsilon(end);
-        }
+        KleenClosure => nfa = kleen_closure(middle),
         Question => {
-            let start = new_epsilon(&mut nfa, Vec::new());
+            let start = nfa.push_epsilon(Vec::new());
             let middle = add_nfa(&mut nfa, middle);
-            let end = new_epsilon(&mut nfa, Vec::new());
+            let end = nfa.push_epsilon(Vec::new());
             nfa[start].add_epsilon(middle.start);
             nfa[start].add_epsilon(end);
             nfa[middle.end].add_epsilon(end);
         }
         Plus => {
             let first = add_nfa(&mut nfa, middle.clone());
-            let start = new_epsilon(&mut nfa, Vec::new());
+            let start = nfa.push_epsilon(Vec::new());
             nfa[first.end].add_epsilon(start);
             let middle = add_nfa(&mut nfa, middle);
-            let end = new_epsilon(&mut nfa, vec![start]);
+            let end = nfa.push_epsilon(vec![start]);
             nfa[start].add_epsilon(middle.start);
             nfa[start].add_epsilon(end);
             nfa[middle.end].add_epsilon(end);
@@ -124,16 +612,25 @@ fn construct_unary_op(rast: &RAST, op: UnaryOperation) -> NFA {
                 let next = add_nfa(&mut nfa, middle.clone());
                 nfa[at.end].add_epsilon(next.start);
                 at = next;
+                check_states(&nfa, max_states)?;
+            }
+        }
+        AtLeast(min) => {
+            nfa = kleen_closure(middle.clone());
+            for _ in 0..min {
+                nfa = concat(middle.clone(), nfa);
+                check_states(&nfa, max_states)?;
             }
         }
         MinMax(min, max) => {
             let mut at = Range { start: 0, end: 0 };
-            new_epsilon(&mut nfa, Vec::new());
+            nfa.push_epsilon(Vec::new());
             // start from one because at is already the first one added
             for _ in 0..min {
                 let next = add_nfa(&mut nfa, middle.clone());
                 nfa[at.end].add_epsilon(next.start);
                 at = next;
+                check_states(&nfa, max_states)?;
             }
             let mut hook_to_end = Vec::new();
             for _ in min..max {
@@ -141,6 +638,7 @@ fn construct_unary_op(rast: &RAST, op: UnaryOperation) -> NFA {
                 let next = add_nfa(&mut nfa, middle.clone());
                 nfa[at.end].add_epsilon(next.start);
                 at = next;
+                check_states(&nfa, max_states)?;
             }
             let end = at.end;
 
@@ -149,14 +647,109 @@ fn construct_unary_op(rast: &RAST, op: UnaryOperation) -> NFA {
             }
         }
     }
-    nfa
+    Ok(nfa)
+}
+
+/// Builds a trie shaped NFA that accepts any of the words. The second
+/// element is the node each word ends on, by index into words, so the
+/// word that matched can be found by looking for its node in the states
+/// left after simulating
+pub fn literal_set_nfa(words: &[&str]) -> (NFA, Vec<usize>) {
+    let mut nfa = Vec::new();
+    let mut tags = vec![0; words.len()];
+    let words: Vec<(&[u8], usize)> = words
+        .iter()
+        .enumerate()
+        .map(|(index, word)| (word.as_bytes(), index))
+        .collect();
+    let ends = add_trie_node(&mut nfa, &words, &mut tags);
+    let finish = nfa.push_epsilon(Vec::new());
+    for end in ends {
+        nfa[end].add_epsilon(finish);
+    }
+    (nfa, tags)
+}
+
+/// NFA accepting any of the words with shared prefixes only built once
+pub fn literal_alternation_nfa(words: &[&str]) -> NFA {
+    literal_set_nfa(words).0
+}
+
+/// Adds a node for the common prefix of words and returns the nodes words end on
+fn add_trie_node(nfa: &mut NFA, words: &[(&[u8], usize)], tags: &mut Vec<usize>) -> Vec<usize> {
+    let node = nfa.push_epsilon(Vec::new());
+    let mut ends = Vec::new();
+    let mut children: BTreeMap<u8, Vec<(&[u8], usize)>> = BTreeMap::new();
+    for (word, index) in words {
+        match word.split_first() {
+            Some((first, rest)) => children.entry(*first).or_default().push((rest, *index)),
+            None => {
+                tags[*index] = node;
+                if !ends.contains(&node) {
+                    ends.push(node);
+                }
+            }
+        }
+    }
+    for (byte, rest) in children {
+        let character = nfa.push_char(byte, nfa.len() + 1);
+        nfa[node].add_epsilon(character);
+        ends.append(&mut add_trie_node(nfa, &rest, tags));
+    }
+    ends
+}
+
+/// Builds an NFA out of smaller pieces, each piece is concatenated
+/// onto the end of what has been built so far
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NfaBuilder {
+    nfa: Option<NFA>,
+}
+
+impl NfaBuilder {
+    pub fn new() -> NfaBuilder {
+        NfaBuilder { nfa: None }
+    }
+
+    fn push(&mut self, next: NFA) -> &mut NfaBuilder {
+        self.nfa = Some(match self.nfa.take() {
+            Some(nfa) => concat(nfa, next),
+            None => next,
+        });
+        self
+    }
+
+    pub fn literal(&mut self, byte: u8) -> &mut NfaBuilder {
+        self.push(vec![Character(byte, 1), Epsilon(Vec::new())])
+    }
+
+    /// With no branches the result accepts nothing
+    pub fn alternate(&mut self, branches: Vec<NFA>) -> &mut NfaBuilder {
+        self.push(alternate_nfa(branches))
+    }
+
+    pub fn concat(&mut self, parts: Vec<NFA>) -> &mut NfaBuilder {
+        for part in parts {
+            self.push(part);
+        }
+        self
+    }
+
+    pub fn star(&mut self, inner: NFA) -> &mut NfaBuilder {
+        self.push(kleen_closure(inner))
+    }
+
+    /// An empty builder gives an NFA that only accepts the empty string
+    pub fn build(self) -> NFA {
+        self.nfa
+            .unwrap_or_else(|| vec![Epsilon(vec![1]), Epsilon(Vec::new())])
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::Error;
-    use rand::Rng;
 
     #[test]
     fn test_add_epsilon() {
@@ -183,6 +776,418 @@ mod test {
         assert_eq!(range, Range { start: 2, end: 3 });
     }
 
+    #[test]
+    fn test_remap() {
+        let nfa = vec![Character(b'a', 1), Epsilon(vec![])];
+        assert_eq!(remap(&nfa, 5), vec![Character(b'a', 6), Epsilon(vec![])]);
+        assert_eq!(remap(&nfa, 0), nfa);
+    }
+
+    #[test]
+    fn test_validate() -> Result<(), Error> {
+        validate(&crate::regex::get_nfa("a(b|c)*")?)?;
+
+        let nfa = vec![Character(b'a', 1)];
+        assert_eq!(
+            validate(&nfa),
+            Err(Error::new(
+                "NFA must have at least a start and a finish node"
+            ))
+        );
+
+        let nfa = vec![Character(b'a', 1), Epsilon(vec![2])];
+        assert_eq!(
+            validate(&nfa),
+            Err(Error::new(
+                "NFA has a transition to a node that doesn't exist"
+            ))
+        );
+
+        let nfa = vec![Epsilon(vec![1]), Character(b'a', 0)];
+        assert_eq!(
+            validate(&nfa),
+            Err(Error::new(
+                "NFA finish node cannot have a character transition"
+            ))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn max_states() -> Result<(), Error> {
+        let options = crate::regex::Options {
+            max_states: 10_000,
+            ..crate::regex::Options::default()
+        };
+        let regex = "(a|b|c|d|e|f|g|h|i|j|k|l|m|n|o|p){200}";
+        assert_eq!(
+            crate::regex::get_nfa_opts(regex, &options),
+            Err(Error::new("NFA exceeded max_states"))
+        );
+
+        let regex = "a(b|c)*";
+        assert_eq!(
+            crate::regex::get_nfa_opts(regex, &options)?,
+            crate::regex::get_nfa(regex)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn builder() -> Result<(), Error> {
+        let mut b = NfaBuilder::new();
+        b.literal(b'b');
+        let mut c = NfaBuilder::new();
+        c.literal(b'c');
+        let mut b_or_c = NfaBuilder::new();
+        b_or_c.alternate(vec![b.build(), c.build()]);
+        let mut nfa = NfaBuilder::new();
+        nfa.literal(b'a').star(b_or_c.build());
+        assert_eq!(nfa.build(), crate::regex::get_nfa("a(b|c)*")?);
+
+        let mut nfa = NfaBuilder::new();
+        nfa.concat(vec![
+            crate::regex::get_nfa("ab")?,
+            crate::regex::get_nfa("c|d|e")?,
+        ]);
+        assert_eq!(nfa.build(), crate::regex::get_nfa("ab(c|d|e)")?);
+
+        let mut nfa = NfaBuilder::new();
+        nfa.alternate(vec![
+            crate::regex::get_nfa("a")?,
+            crate::regex::get_nfa("b")?,
+            crate::regex::get_nfa("c")?,
+        ]);
+        assert_eq!(nfa.build(), crate::regex::get_nfa("a|b|c")?);
+
+        validate(&NfaBuilder::new().build())?;
+
+        let mut nfa = NfaBuilder::new();
+        nfa.alternate(Vec::new());
+        let nfa = nfa.build();
+        validate(&nfa)?;
+        assert!(!crate::regex::simulate::matches(&nfa, b""));
+        Ok(())
+    }
+
+    #[test]
+    fn test_concat_nfa() -> Result<(), Error> {
+        let ab = crate::regex::get_nfa("ab")?;
+        let cd = crate::regex::get_nfa("cd")?;
+        assert_eq!(concat_nfa(&ab, &cd), crate::regex::get_nfa("abcd")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_alternate_star_nfa() -> Result<(), Error> {
+        let ab = crate::regex::get_nfa("ab")?;
+        let cd = crate::regex::get_nfa("cd")?;
+        let nfa = star_nfa(&alternate_nfa(vec![ab, cd]));
+        assert_eq!(nfa, crate::regex::get_nfa("(ab|cd)*")?);
+
+        let parts = vec![
+            crate::regex::get_nfa("a")?,
+            crate::regex::get_nfa("b")?,
+            crate::regex::get_nfa("c")?,
+        ];
+        assert_eq!(alternate_nfa(parts), crate::regex::get_nfa("a|b|c")?);
+
+        use crate::regex::simulate::{matches, sample_strings};
+        let nothing = alternate_nfa(Vec::new());
+        validate(&nothing)?;
+        assert!(!matches(&nothing, b""));
+        assert!(sample_strings(&nothing, 10).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_literal_set_nfa() {
+        use crate::regex::simulate::{matches, simulate};
+
+        let words = ["if", "in", "int"];
+        let (nfa, tags) = literal_set_nfa(&words);
+        assert_eq!(validate(&nfa), Ok(()));
+        for (index, word) in words.iter().enumerate() {
+            assert!(matches(&nfa, word.as_bytes()));
+            let states = simulate(&nfa, word.as_bytes());
+            let found: Vec<usize> = (0..words.len())
+                .filter(|i| states.contains(&tags[*i]))
+                .collect();
+            assert_eq!(found, vec![index]);
+        }
+        assert!(!matches(&nfa, b"i"));
+        assert!(!matches(&nfa, b"ints"));
+        assert!(!matches(&nfa, b""));
+    }
+
+    #[test]
+    fn iterating() -> Result<(), Error> {
+        let nfa = crate::regex::get_nfa("ab")?;
+        let mut seen = Vec::new();
+        for (node, transition) in nfa.iter_transitions() {
+            let kind = match transition {
+                Epsilon(_) => 'e',
+                Character(c, _) => *c as char,
+                Transition::Set(_, _) => 's',
+            };
+            seen.push((node, kind));
+        }
+        assert_eq!(seen, [(0, 'a'), (1, 'e'), (2, 'b'), (3, 'e')]);
+        Ok(())
+    }
+
+    #[test]
+    fn pushing() {
+        // a[bc]*
+        let mut nfa = NFA::new();
+        nfa.push_char(b'a', 1);
+        let mut set = ByteSet::new();
+        set.insert(b'b');
+        set.insert(b'c');
+        let body = nfa.push_epsilon(vec![2, 3]);
+        assert_eq!(nfa.push_set(set, body), 2);
+        nfa.push_epsilon(Vec::new());
+        assert_eq!(validate(&nfa), Ok(()));
+
+        use crate::regex::simulate::matches;
+        assert!(matches(&nfa, b"a"));
+        assert!(matches(&nfa, b"abcb"));
+        assert!(!matches(&nfa, b"ad"));
+    }
+
+    #[test]
+    fn test_literal_alternation_nfa() -> Result<(), Error> {
+        let trie = literal_alternation_nfa(&["cat", "car", "dog"]);
+        let naive = crate::regex::get_nfa("cat|car|dog")?;
+        assert!(trie.len() < naive.len());
+        assert!(crate::regex::simulate::languages_agree_up_to(
+            &trie, &naive, 4
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn deep_rast() {
+        let mut rast = Atomic(b'a');
+        for _ in 0..10_000 {
+            rast = Binary(Box::new(rast), Box::new(Atomic(b'a')), Concat);
+        }
+        let nfa = rast_to_nfa(&rast);
+        assert_eq!(nfa.len(), 2 * 10_001);
+    }
+
+    #[test]
+    fn deep_groups() -> Result<(), Error> {
+        let regex = format!("{}a{}", "(".repeat(10_000), ")".repeat(10_000));
+        let nfa = crate::regex::get_nfa(&regex)?;
+        assert_eq!(nfa, crate::regex::get_nfa("a")?);
+
+        let regex = format!("{}ab{}+", "(".repeat(10_000), ")".repeat(10_000));
+        let nfa = crate::regex::get_nfa(&regex)?;
+        assert_eq!(nfa, crate::regex::get_nfa("(ab)+")?);
+        Ok(())
+    }
+
+    #[test]
+    fn long_regex() -> Result<(), Error> {
+        let literal = "ab".repeat(2_500);
+        let nfa = crate::regex::get_nfa(&literal)?;
+        assert!(crate::regex::simulate::matches(&nfa, literal.as_bytes()));
+        assert!(!crate::regex::simulate::matches(&nfa, b"ab"));
+
+        let alternation = vec!["ab"; 5_000].join("|");
+        let nfa = crate::regex::get_nfa(&alternation)?;
+        assert!(crate::regex::simulate::matches(&nfa, b"ab"));
+        Ok(())
+    }
+
+    #[test]
+    fn inverse_set() -> Result<(), Error> {
+        let nfa = crate::regex::get_nfa("[^aeiou]")?;
+        assert_eq!(nfa.len(), 2);
+        let set = match nfa[0] {
+            Transition::Set(set, 1) => set,
+            _ => panic!("Unexpected transition"),
+        };
+        assert!(set.contains(b'b'));
+        assert!(!set.contains(b'a'));
+
+        use crate::regex::simulate::matches;
+        for consonant in b"bcdfz".iter() {
+            assert!(matches(&nfa, &[*consonant]));
+        }
+        for vowel in b"aeiou".iter() {
+            assert!(!matches(&nfa, &[*vowel]));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn full_range_set() -> Result<(), Error> {
+        let nfa = crate::regex::get_nfa(r"[\000-\377]")?;
+        assert_eq!(
+            nfa,
+            vec![Transition::Set(ByteSet::new().invert(), 1), Epsilon(vec![])]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_structural_hash() -> Result<(), Error> {
+        let first = crate::regex::get_nfa("a(b|c)*")?;
+        let second = crate::regex::get_nfa("a(b|c)*")?;
+        assert_eq!(structural_hash(&first), structural_hash(&second));
+
+        let other = crate::regex::get_nfa("a+")?;
+        assert_ne!(structural_hash(&first), structural_hash(&other));
+        Ok(())
+    }
+
+    #[test]
+    fn test_accepting_states() -> Result<(), Error> {
+        let nfa = crate::regex::get_nfa("ab")?;
+        assert_eq!(accepting_states(&nfa), [3]);
+        let nfa = crate::regex::get_nfa("ab?")?;
+        assert_eq!(accepting_states(&nfa), [1, 2, 4, 5]);
+
+        // a union whose branches each end on their own node before a shared finish
+        let first = crate::regex::get_nfa("a")?;
+        let second = crate::regex::get_nfa("b")?;
+        let mut union = vec![Epsilon(vec![1, 3])];
+        union.extend(remap(&first, 1));
+        union.extend(remap(&second, 3));
+        union[2] = Epsilon(vec![5]);
+        union[4] = Epsilon(vec![5]);
+        union.push(Epsilon(vec![]));
+        assert_eq!(accepting_states(&union), [2, 4, 5]);
+        Ok(())
+    }
+
+    #[test]
+    fn empty_nfa() {
+        let empty = NFA::new();
+        assert!(accepting_states(&empty).is_empty());
+        assert!(canonical(&empty).is_empty());
+        assert!(dedup_states(&empty).is_empty());
+        assert!(literal_prefix(&empty).is_empty());
+        assert!(!crate::regex::simulate::matches(&empty, b""));
+        assert!(crate::regex::simulate::sample_strings(&empty, 10).is_empty());
+    }
+
+    #[test]
+    fn test_dedup_states() -> Result<(), Error> {
+        let nfa = crate::regex::get_nfa("(a|a|a)")?;
+        let deduped = dedup_states(&nfa);
+        assert!(deduped.len() < nfa.len());
+        assert_eq!(validate(&deduped), Ok(()));
+
+        let patterns = [
+            "(a|a|a)",
+            "a(b|c)*",
+            "(ab|ab)+c?",
+            "[b-d]{2,3}",
+            "(a|b)(a|b)",
+        ];
+        for pattern in patterns.iter() {
+            let nfa = crate::regex::get_nfa(pattern)?;
+            let deduped = dedup_states(&nfa);
+            assert!(deduped.len() <= nfa.len());
+            assert!(crate::regex::simulate::languages_agree_up_to(
+                &nfa, &deduped, 5
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_canonical() -> Result<(), Error> {
+        let a = vec![Character(b'a', 1), Epsilon(Vec::new())];
+        let b = vec![Character(b'b', 1), Epsilon(Vec::new())];
+        let parsed = crate::regex::get_nfa("a|b")?;
+        let combined = alternate_nfa(vec![b, a]);
+        assert_ne!(parsed, combined);
+        assert_eq!(canonical(&parsed), canonical(&combined));
+
+        // the same NFA as "ab" with its middle nodes swapped around
+        let shuffled = vec![
+            Character(b'a', 2),
+            Character(b'b', 3),
+            Epsilon(vec![1]),
+            Epsilon(Vec::new()),
+        ];
+        assert_eq!(canonical(&shuffled), crate::regex::get_nfa("ab")?);
+        assert_ne!(
+            canonical(&parsed),
+            canonical(&crate::regex::get_nfa("a|c")?)
+        );
+
+        // nodes only reachable through the finish are still kept
+        let past_finish = vec![Epsilon(vec![2]), Character(b'x', 2), Epsilon(vec![1])];
+        assert_eq!(validate(&past_finish), Ok(()));
+        assert_eq!(
+            canonical(&past_finish),
+            [Epsilon(vec![2]), Character(b'x', 2), Epsilon(vec![1])]
+        );
+
+        // both branches start with an epsilon node
+        let parsed = crate::regex::get_nfa("a*|b*")?;
+        let a = crate::regex::get_nfa("a*")?;
+        let b = crate::regex::get_nfa("b*")?;
+        let combined = alternate_nfa(vec![b, a]);
+        assert_ne!(parsed, combined);
+        assert_eq!(canonical(&parsed), canonical(&combined));
+        assert_ne!(
+            canonical(&parsed),
+            canonical(&crate::regex::get_nfa("a*|c*")?)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_used_bytes() -> Result<(), Error> {
+        let nfa = crate::regex::get_nfa("[a-c]+")?;
+        let mut expected = ByteSet::new();
+        expected.insert(b'a');
+        expected.insert(b'b');
+        expected.insert(b'c');
+        assert_eq!(used_bytes(&nfa), expected);
+
+        let nfa = crate::regex::get_nfa("[^a]")?;
+        assert!(!used_bytes(&nfa).contains(b'a'));
+        assert!(used_bytes(&nfa).contains(b'b'));
+        Ok(())
+    }
+
+    #[test]
+    fn test_literal_prefix() -> Result<(), Error> {
+        let prefix = |regex| -> Result<Vec<u8>, Error> {
+            Ok(literal_prefix(&crate::regex::get_nfa(regex)?))
+        };
+        assert_eq!(prefix("abc.*")?, b"abc");
+        assert_eq!(prefix("abc[0-9]+")?, b"abc");
+        assert_eq!(prefix("a|b")?, b"");
+        assert_eq!(prefix("ab|ac")?, b"a");
+        assert_eq!(prefix("a+b")?, b"a");
+        assert_eq!(prefix("(ab)?c")?, b"");
+        assert_eq!(prefix("abc")?, b"abc");
+        Ok(())
+    }
+
+    #[test]
+    fn test_required_literal() -> Result<(), Error> {
+        let required = |regex| -> Result<Option<Vec<u8>>, Error> {
+            Ok(required_literal(&crate::regex::get_nfa(regex)?))
+        };
+        assert_eq!(required(".*foo.*")?, Some(b"foo".to_vec()));
+        assert_eq!(required("a|b")?, None);
+        assert_eq!(required("abc")?, Some(b"abc".to_vec()));
+        assert_eq!(required("x(a|b)yz")?, Some(b"yz".to_vec()));
+        assert_eq!(required("a+b")?, Some(b"a".to_vec()));
+        assert_eq!(required("(ab)*")?, None);
+        assert_eq!(required("[^a]")?, None);
+        Ok(())
+    }
+
     #[test]
     fn atomic() -> Result<(), Error> {
         let regex = "a";
@@ -325,6 +1330,18 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn unary_at_least() -> Result<(), Error> {
+        let regex = "a{2,}";
+        let nfa = crate::regex::get_nfa(regex)?;
+        assert_eq!(nfa, crate::regex::get_nfa("aaa*")?);
+
+        let regex = "a{0,}";
+        let nfa = crate::regex::get_nfa(regex)?;
+        assert_eq!(nfa, crate::regex::get_nfa("a*")?);
+        Ok(())
+    }
+
     #[test]
     fn test_combo() -> Result<(), Error> {
         let regex = "a(b|c)*";
@@ -348,16 +1365,18 @@ mod test {
     }
 
     #[test]
-    #[allow(unused_must_use)]
     fn monkey() {
-        let mut rng = rand::thread_rng();
-        for _ in 0..10000 {
-            let length = rng.gen_range(0, 16);
-            let mut regex = String::new();
-            for _ in 0..length {
-                regex.push(rng.gen_range(32, 127) as u8 as char);
+        crate::regex::monkey::run(10000, |regex| {
+            let _ = crate::regex::get_nfa(regex);
+        });
+    }
+
+    #[test]
+    fn monkey_validates() {
+        crate::regex::monkey::run(10000, |regex| {
+            if let Ok(nfa) = crate::regex::get_nfa(regex) {
+                assert_eq!(validate(&nfa), Ok(()), "invalid NFA for {:?}", regex);
             }
-            crate::regex::get_nfa(&regex);
-        }
+        });
     }
 }
@@ -1,3 +1,4 @@
+use super::byteset::ByteSet;
 use super::simplify::Token;
 use crate::Error;
 use BinaryOperation::*;
@@ -13,6 +14,7 @@ pub enum BinaryOperation {
 pub enum UnaryOperation {
     MinMax(u8, u8),
     Times(u8),
+    AtLeast(u8),
     KleenClosure,
     Question,
     Plus,
@@ -23,9 +25,13 @@ pub enum RAST {
     Binary(Box<RAST>, Box<RAST>, BinaryOperation),
     Unary(Box<RAST>, UnaryOperation),
     Atomic(u8),
+    Set(ByteSet),
+    /// Matches only the empty string, from a branch like (a|)
+    Empty,
 }
 
 pub fn parse(regex: &[Token]) -> Result<Box<RAST>, Error> {
+    check_parens(regex)?;
     let mut regex: Vec<Token> = regex.iter().cloned().rev().collect();
     let rast = parse_regex(&mut regex)?;
     if !regex.is_empty() {
@@ -34,77 +40,250 @@ pub fn parse(regex: &[Token]) -> Result<Box<RAST>, Error> {
     Ok(Box::new(rast))
 }
 
+/// A ')' without a '(' before it is an error here so the parser only ever
+/// sees one inside a group
+fn check_parens(regex: &[Token]) -> Result<(), Error> {
+    let mut depth = 0usize;
+    for token in regex {
+        match token {
+            Token::LParen => depth += 1,
+            Token::RParen if depth == 0 => {
+                return Err(Error::new("Unexpected ')' with no matching '('"))
+            }
+            Token::RParen => depth -= 1,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Parses with a stack of the groups that are still open instead of
+/// recursing, so deeply nested or very long regexes can't overflow the stack
 pub fn parse_regex(regex: &mut Vec<Token>) -> Result<RAST, Error> {
-    parse_altern(regex)
+    let mut open: Vec<Group> = Vec::new();
+    let mut group = Group::default();
+    loop {
+        let mut operand = match parse_group(regex)? {
+            Some(operand) => operand,
+            None => {
+                open.push(std::mem::take(&mut group));
+                continue;
+            }
+        };
+        loop {
+            group.concat.push(parse_unary(regex, operand)?);
+            match regex.last() {
+                Some(Token::Concat) => {
+                    regex.pop();
+                    break;
+                }
+                Some(Token::Alternation) => {
+                    regex.pop();
+                    let concat = std::mem::take(&mut group.concat);
+                    group.branches.push(right_associate(concat, Concat));
+                    break;
+                }
+                _ => (),
+            }
+            let parent = match open.pop() {
+                Some(parent) => parent,
+                None => return Ok(group.close()),
+            };
+            match regex.pop() {
+                Some(Token::RParen) => (),
+                Some(_) => return Err(Error::new("Unexpected token, expected ')'")),
+                None => return Err(Error::new("Missing ')' to close '('")),
+            }
+            operand = std::mem::replace(&mut group, parent).close();
+        }
+    }
 }
 
-fn parse_altern(regex: &mut Vec<Token>) -> Result<RAST, Error> {
-    let concat = parse_concat(regex)?;
-    if let Some(prime) = parse_altern_prime(regex)? {
-        Ok(RAST::Binary(Box::new(concat), Box::new(prime.0), prime.1))
-    } else {
-        Ok(concat)
+/// What has been parsed so far of a group that is still open
+#[derive(Default)]
+struct Group {
+    branches: Vec<RAST>,
+    concat: Vec<RAST>,
+}
+
+impl Group {
+    fn close(mut self) -> RAST {
+        self.branches.push(right_associate(self.concat, Concat));
+        right_associate(self.branches, Alternation)
     }
 }
 
-fn parse_altern_prime(regex: &mut Vec<Token>) -> Result<Option<(RAST, BinaryOperation)>, Error> {
-    if let Some(t) = regex.pop() {
-        let token = match t {
-            Token::Alternation => Alternation,
-            _ => {
-                regex.push(t);
-                return Ok(None);
+/// Chains operands together to the right, a(b(c)) for three operands
+fn right_associate(operands: Vec<RAST>, op: BinaryOperation) -> RAST {
+    let mut operands = operands.into_iter().rev();
+    let last = operands
+        .next()
+        .expect("Programmer Error: A group always has an operand before it closes");
+    operands.fold(last, |right, left| {
+        RAST::Binary(Box::new(left), Box::new(right), op)
+    })
+}
+
+/// Calls f on every node of the RAST in pre-order
+pub fn walk<F: FnMut(&RAST)>(rast: &RAST, f: &mut F) {
+    f(rast);
+    match rast {
+        RAST::Binary(left, right, _) => {
+            walk(left, f);
+            walk(right, f);
+        }
+        RAST::Unary(inner, _) => walk(inner, f),
+        RAST::Atomic(_) => (),
+        RAST::Set(_) => (),
+        RAST::Empty => (),
+    }
+}
+
+/// Applies algebraic simplifications that don't change what the regex matches:
+///   a{1}     -> a
+///   a|a      -> a    (repeated branches anywhere in an alternation)
+///   (ab)c    -> a(bc) (nested concats and alternations are right associative)
+/// groups don't show up in the RAST so they never need removing
+pub fn canonicalize(rast: RAST) -> RAST {
+    match rast {
+        RAST::Binary(left, right, op) => {
+            let mut operands = Vec::new();
+            flatten(*left, op, &mut operands);
+            flatten(*right, op, &mut operands);
+            if op == Alternation {
+                let mut unique: Vec<RAST> = Vec::new();
+                for operand in operands {
+                    if !unique.contains(&operand) {
+                        unique.push(operand);
+                    }
+                }
+                operands = unique;
             }
-        };
-        let concat = parse_concat(regex)?;
-        if let Some(prime) = parse_altern_prime(regex)? {
-            Ok(Some((
-                RAST::Binary(Box::new(concat), Box::new(prime.0), prime.1),
-                token,
-            )))
-        } else {
-            Ok(Some((concat, token)))
+            let last = operands.pop().expect("binary always has operands");
+            operands.into_iter().rev().fold(last, |right, left| {
+                RAST::Binary(Box::new(left), Box::new(right), op)
+            })
         }
-    } else {
-        Ok(None)
+        RAST::Unary(inner, Times(1)) => canonicalize(*inner),
+        RAST::Unary(inner, op) => RAST::Unary(Box::new(canonicalize(*inner)), op),
+        RAST::Atomic(c) => RAST::Atomic(c),
+        RAST::Set(set) => RAST::Set(set),
+        RAST::Empty => RAST::Empty,
     }
 }
 
-fn parse_concat(regex: &mut Vec<Token>) -> Result<RAST, Error> {
-    let unary = parse_unary(regex)?;
-    if let Some(prime) = parse_concat_prime(regex)? {
-        Ok(RAST::Binary(Box::new(unary), Box::new(prime.0), prime.1))
-    } else {
-        Ok(unary)
+/// The RAST for the regex read backwards, every concatenation is flipped
+pub fn reverse(rast: RAST) -> RAST {
+    match rast {
+        RAST::Binary(left, right, Concat) => {
+            RAST::Binary(Box::new(reverse(*right)), Box::new(reverse(*left)), Concat)
+        }
+        RAST::Binary(left, right, op) => {
+            RAST::Binary(Box::new(reverse(*left)), Box::new(reverse(*right)), op)
+        }
+        RAST::Unary(inner, op) => RAST::Unary(Box::new(reverse(*inner)), op),
+        other => other,
     }
 }
 
-fn parse_concat_prime(regex: &mut Vec<Token>) -> Result<Option<(RAST, BinaryOperation)>, Error> {
-    if let Some(t) = regex.pop() {
-        let token = match t {
-            Token::Concat => Concat,
-            _ => {
-                regex.push(t);
-                return Ok(None);
+/// Collects the canonical operands of a chain of op
+fn flatten(rast: RAST, op: BinaryOperation, operands: &mut Vec<RAST>) {
+    match canonicalize(rast) {
+        RAST::Binary(left, right, inner) if inner == op => {
+            flatten(*left, op, operands);
+            flatten(*right, op, operands);
+        }
+        other => operands.push(other),
+    }
+}
+
+/// True when an unbounded repetition wraps something that can match the same
+/// text in more than one way, like (a+)+ or (a|a)*. The NFA handles these fine
+/// but a backtracking engine would take exponential time on them
+pub fn has_ambiguous_repetition(rast: &RAST) -> bool {
+    let mut found = false;
+    walk(rast, &mut |node| {
+        if let RAST::Unary(inner, op) = node {
+            if is_unbounded(*op) && (repeats_itself(inner) || has_overlapping_branches(inner)) {
+                found = true;
             }
-        };
-        let unary = parse_unary(regex)?;
-        if let Some(prime) = parse_concat_prime(regex)? {
-            Ok(Some((
-                RAST::Binary(Box::new(unary), Box::new(prime.0), prime.1),
-                token,
-            )))
-        } else {
-            Ok(Some((unary, token)))
         }
-    } else {
-        Ok(None)
+    });
+    found
+}
+
+fn is_unbounded(op: UnaryOperation) -> bool {
+    matches!(op, KleenClosure | Plus | AtLeast(_))
+}
+
+/// Whether the rast has an unbounded repetition that can make up the whole match
+fn repeats_itself(rast: &RAST) -> bool {
+    match rast {
+        RAST::Unary(_, op) if is_unbounded(*op) => true,
+        RAST::Unary(inner, _) => repeats_itself(inner),
+        RAST::Binary(left, right, Concat) => {
+            (repeats_itself(left) && nullable(right)) || (nullable(left) && repeats_itself(right))
+        }
+        RAST::Binary(left, right, Alternation) => repeats_itself(left) || repeats_itself(right),
+        RAST::Atomic(_) => false,
+        RAST::Set(_) => false,
+        RAST::Empty => false,
     }
 }
 
-fn parse_unary(regex: &mut Vec<Token>) -> Result<RAST, Error> {
-    let group = parse_group(regex)?;
-    let opperation = parse_unary_prime(regex)?;
+/// Whether any alternation has two branches that can start with the same byte
+fn has_overlapping_branches(rast: &RAST) -> bool {
+    let mut found = false;
+    walk(rast, &mut |node| {
+        if let RAST::Binary(left, right, Alternation) = node {
+            let right = first_bytes(right);
+            if first_bytes(left).iter().any(|byte| right.contains(byte)) {
+                found = true;
+            }
+        }
+    });
+    found
+}
+
+/// Bytes a match of the rast can start with
+fn first_bytes(rast: &RAST) -> ByteSet {
+    match rast {
+        RAST::Binary(left, right, Concat) if nullable(left) => {
+            first_bytes(left).union(&first_bytes(right))
+        }
+        RAST::Binary(left, _, Concat) => first_bytes(left),
+        RAST::Binary(left, right, Alternation) => first_bytes(left).union(&first_bytes(right)),
+        RAST::Unary(inner, _) => first_bytes(inner),
+        RAST::Atomic(c) => {
+            let mut set = ByteSet::new();
+            set.insert(*c);
+            set
+        }
+        RAST::Set(set) => *set,
+        RAST::Empty => ByteSet::new(),
+    }
+}
+
+/// Whether the rast can match the empty string
+fn nullable(rast: &RAST) -> bool {
+    match rast {
+        RAST::Binary(left, right, Concat) => nullable(left) && nullable(right),
+        RAST::Binary(left, right, Alternation) => nullable(left) || nullable(right),
+        RAST::Unary(_, KleenClosure) | RAST::Unary(_, Question) => true,
+        RAST::Unary(_, MinMax(0, _)) | RAST::Unary(_, AtLeast(0)) => true,
+        RAST::Unary(inner, _) => nullable(inner),
+        RAST::Atomic(_) => false,
+        RAST::Set(_) => false,
+        RAST::Empty => true,
+    }
+}
+
+fn parse_unary(regex: &mut Vec<Token>, group: RAST) -> Result<RAST, Error> {
+    let mut opperation = parse_unary_prime(regex)?;
+    while let Some(merged) = opperation.and_then(|op| merge_unary(op, regex.last())) {
+        regex.pop();
+        opperation = Some(merged);
+    }
     Ok(if let Some(opperation) = opperation {
         RAST::Unary(Box::new(group), opperation)
     } else {
@@ -119,6 +298,8 @@ fn parse_unary_prime(regex: &mut Vec<Token>) -> Result<Option<UnaryOperation>, E
             Token::Question => Some(Question),
             Token::Plus => Some(Plus),
             Token::Times(min) => Some(Times(min)),
+            Token::AtLeast(min) => Some(AtLeast(min)),
+            Token::MinMax(min, max) if min == max && min > 0 => Some(Times(min)),
             Token::MinMax(min, max) => Some(MinMax(min, max)),
             _ => {
                 regex.push(t);
@@ -130,21 +311,47 @@ fn parse_unary_prime(regex: &mut Vec<Token>) -> Result<Option<UnaryOperation>, E
     })
 }
 
-fn parse_group(regex: &mut Vec<Token>) -> Result<RAST, Error> {
+/// Stacked quantifiers that mean the same thing as a single quantifier
+/// get merged into it:
+///   a**  -> a*
+///   a*+  -> a*
+///   a+*  -> a*
+///   a?*  -> a*
+/// A + after any other quantifier makes it possessive (a++, a?+, a{2}+),
+/// which changes nothing when nothing backtracks so the + is dropped.
+/// anything else stacked (like a*? or a{2}*) is still an error
+fn merge_unary(first: UnaryOperation, second: Option<&Token>) -> Option<UnaryOperation> {
+    match (first, second?) {
+        (KleenClosure, Token::KleenClosure) => Some(KleenClosure),
+        (Plus, Token::KleenClosure) => Some(KleenClosure),
+        (Question, Token::KleenClosure) => Some(KleenClosure),
+        (op, Token::Plus) => Some(op),
+        _ => None,
+    }
+}
+
+/// A single operand, or None after a '(' that opens a group
+fn parse_group(regex: &mut Vec<Token>) -> Result<Option<RAST>, Error> {
     if let Some(t) = regex.pop() {
         match t {
-            Token::Character(c) => Ok(RAST::Atomic(c)),
+            Token::Character(c) => Ok(Some(RAST::Atomic(c))),
+            Token::Set(set) => Ok(Some(RAST::Set(set))),
+            Token::Empty => Ok(Some(RAST::Empty)),
             Token::LParen => {
-                let group = parse_regex(regex)?;
-                if let Some(t) = regex.pop() {
-                    match t {
-                        Token::RParen => Ok(group),
-                        _ => Err(Error::new("Unexpected token, expected ')'")),
-                    }
-                } else {
-                    Err(Error::new("Reached end of regex while parsing"))
+                if regex.last() == Some(&Token::RParen) {
+                    return Err(Error::new("Empty group ()"));
                 }
+                Ok(None)
             }
+            // a ')' can only get here right after the '|' in a group like (a|)
+            Token::RParen => Err(Error::new("Alternation with nothing on one side")),
+            Token::KleenClosure
+            | Token::Question
+            | Token::Plus
+            | Token::Times(_)
+            | Token::MinMax(_, _)
+            | Token::AtLeast(_) => Err(Error::new("Quantifier with nothing to repeat")),
+            Token::Alternation => Err(Error::new("Alternation with nothing on one side")),
             _ => Err(Error::new("Unexpected token, expected char or '('")),
         }
     } else {
@@ -157,7 +364,6 @@ mod test {
     use super::RAST::*;
     use super::*;
     use crate::Error;
-    use rand::Rng;
 
     #[test]
     fn basic() -> Result<(), Error> {
@@ -245,16 +451,149 @@ mod test {
     }
 
     #[test]
-    #[allow(unused_must_use)]
-    fn monkey() {
-        let mut rng = rand::thread_rng();
-        for _ in 0..10000 {
-            let length = rng.gen_range(0, 16);
-            let mut regex = String::new();
-            for _ in 0..length {
-                regex.push(rng.gen_range(32, 127) as u8 as char);
+    fn walking() -> Result<(), Error> {
+        let regex = crate::regex::get_rast("a(b|c)*d")?;
+        let mut atomics = 0;
+        walk(&regex, &mut |node| {
+            if let Atomic(_) = node {
+                atomics += 1;
+            }
+        });
+        assert_eq!(atomics, 4);
+
+        let mut order = Vec::new();
+        walk(&crate::regex::get_rast("a|b*")?, &mut |node| {
+            order.push(match node {
+                Binary(_, _, _) => 'B',
+                Unary(_, _) => 'U',
+                Atomic(c) => *c as char,
+                Set(_) => 'S',
+                Empty => 'E',
+            })
+        });
+        assert_eq!(order, ['B', 'a', 'U', 'b']);
+        Ok(())
+    }
+
+    #[test]
+    fn stacked_unary() -> Result<(), Error> {
+        let expected = Unary(Box::new(Atomic(b'a')), KleenClosure);
+        assert_eq!(crate::regex::get_rast("a**")?, expected);
+        assert_eq!(crate::regex::get_rast("a*+")?, expected);
+        assert_eq!(crate::regex::get_rast("a+*")?, expected);
+        assert_eq!(crate::regex::get_rast("a?*")?, expected);
+        assert_eq!(crate::regex::get_rast("a*+*")?, expected);
+
+        let regex = "ab**c";
+        let expected = crate::regex::get_rast("ab*c")?;
+        assert_eq!(crate::regex::get_rast(regex)?, expected);
+
+        // possessive quantifiers
+        let get_rast = crate::regex::get_rast;
+        assert_eq!(get_rast("a++b")?, get_rast("a+b")?);
+        assert_eq!(get_rast("a?+")?, get_rast("a?")?);
+        assert_eq!(get_rast("a{2,3}+")?, get_rast("a{2,3}")?);
+        let nfa = crate::regex::get_nfa("a++b")?;
+        assert!(crate::regex::simulate::matches(&nfa, b"ab"));
+        assert!(crate::regex::simulate::matches(&nfa, b"aab"));
+        assert!(!crate::regex::simulate::matches(&nfa, b"b"));
+        Ok(())
+    }
+
+    #[test]
+    fn canonical() -> Result<(), Error> {
+        let get_rast = crate::regex::get_rast;
+        assert_eq!(canonicalize(get_rast("a|a")?), Atomic(b'a'));
+        assert_eq!(canonicalize(get_rast("a{1}")?), Atomic(b'a'));
+        assert_eq!(canonicalize(get_rast("((a))")?), Atomic(b'a'));
+        assert_eq!(canonicalize(get_rast("a|b|a|b")?), get_rast("a|b")?);
+        assert_eq!(canonicalize(get_rast("(ab)c")?), get_rast("abc")?);
+        assert_eq!(canonicalize(get_rast("(a|b)|(c|a)")?), get_rast("a|b|c")?);
+        assert_eq!(canonicalize(get_rast("(a{1}|a)*")?), get_rast("a*")?);
+        assert_eq!(canonicalize(get_rast("a(bc){1}d")?), get_rast("abcd")?);
+
+        let patterns = ["(ab)c|(ab)c", "(a|a){1}b*", "((a|b)|a)(c{1}|d)"];
+        let inputs: [&[u8]; 8] = [b"", b"a", b"b", b"abc", b"ab", b"abbb", b"ac", b"bd"];
+        for pattern in patterns.iter() {
+            let rast = get_rast(pattern)?;
+            let before = crate::regex::nfa::rast_to_nfa(&rast);
+            let after = crate::regex::nfa::rast_to_nfa(&canonicalize(rast));
+            assert!(after.len() <= before.len());
+            for input in inputs.iter() {
+                assert_eq!(
+                    crate::regex::simulate::matches(&before, input),
+                    crate::regex::simulate::matches(&after, input)
+                );
             }
-            crate::regex::get_rast(&regex);
         }
+        Ok(())
+    }
+
+    #[test]
+    fn errors() {
+        let get_rast = crate::regex::get_rast;
+        assert_eq!(
+            get_rast(")a"),
+            Err(Error::new("Unexpected ')' with no matching '('"))
+        );
+        assert_eq!(
+            get_rast("a)"),
+            Err(Error::new("Unexpected ')' with no matching '('"))
+        );
+        assert_eq!(
+            get_rast("(a))b"),
+            Err(Error::new("Unexpected ')' with no matching '('"))
+        );
+        assert_eq!(get_rast("()"), Err(Error::new("Empty group ()")));
+        assert_eq!(get_rast("a()b"), Err(Error::new("Empty group ()")));
+        assert_eq!(
+            get_rast("(a|)"),
+            Err(Error::new("Alternation with nothing on one side"))
+        );
+        assert_eq!(
+            get_rast("(a|)b"),
+            Err(Error::new("Alternation with nothing on one side"))
+        );
+        assert_eq!(
+            get_rast("*a"),
+            Err(Error::new("Quantifier with nothing to repeat"))
+        );
+        assert_eq!(
+            get_rast("a|{2}"),
+            Err(Error::new("Quantifier with nothing to repeat"))
+        );
+        assert_eq!(get_rast("(a"), Err(Error::new("Missing ')' to close '('")));
+        assert_eq!(
+            get_rast("(|a)"),
+            Err(Error::new("Alternation with nothing on one side"))
+        );
+        assert_eq!(
+            get_rast("a|"),
+            Err(Error::new("Reached end of regex while parsing"))
+        );
+    }
+
+    #[test]
+    fn ambiguous_repetition() -> Result<(), Error> {
+        // (a+)+ is rejected by check_rast so parse without it
+        let rast = |regex: &str| -> Result<RAST, Error> {
+            let tokens = crate::regex::scan::scan(regex)?;
+            Ok(*parse(&crate::regex::simplify::simpilfy(&tokens[..])?[..])?)
+        };
+        assert!(has_ambiguous_repetition(&rast("(a+)+")?));
+        assert!(has_ambiguous_repetition(&rast("(a|a)*")?));
+        assert!(has_ambiguous_repetition(&rast("x(b?a*)*")?));
+        assert!(!has_ambiguous_repetition(&rast("(ab)+")?));
+        assert!(!has_ambiguous_repetition(&rast("(ab*)+")?));
+        assert!(!has_ambiguous_repetition(&rast("(a|b)*")?));
+        assert!(!has_ambiguous_repetition(&rast("a+")?));
+        Ok(())
+    }
+
+    #[test]
+    fn monkey() {
+        crate::regex::monkey::run(10000, |regex| {
+            let _ = crate::regex::get_rast(regex);
+        });
     }
 }
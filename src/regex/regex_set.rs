@@ -0,0 +1,73 @@
+use super::nfa::{remap, Transition, NFA};
+use super::simulate::{epsilon_closure, step};
+use crate::Error;
+use std::collections::HashSet;
+
+/// Many regexes joined into one NFA so they can all be checked in a single pass
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegexSet {
+    nfa: NFA,
+    /// accept_map[i] is the finish node of the ith pattern
+    accept_map: Vec<usize>,
+}
+
+impl RegexSet {
+    pub fn new(patterns: &[&str]) -> Result<RegexSet, Error> {
+        let mut nfa = vec![Transition::Epsilon(Vec::new())];
+        let mut starts = Vec::new();
+        let mut accept_map = Vec::new();
+        for pattern in patterns {
+            let part = super::get_nfa(pattern)?;
+            starts.push(nfa.len());
+            let offset = nfa.len();
+            nfa.extend(remap(&part, offset));
+            accept_map.push(nfa.len() - 1);
+        }
+        nfa[0] = Transition::Epsilon(starts);
+        Ok(RegexSet { nfa, accept_map })
+    }
+
+    /// Indices of every pattern that matches somewhere in text
+    pub fn matching(&self, text: &[u8]) -> Vec<usize> {
+        let mut matched = vec![false; self.accept_map.len()];
+        let mut states = HashSet::new();
+        for position in 0..=text.len() {
+            // start a new attempt at every position
+            states.insert(0);
+            epsilon_closure(&self.nfa, &mut states);
+            for (index, accept) in self.accept_map.iter().enumerate() {
+                if states.contains(accept) {
+                    matched[index] = true;
+                }
+            }
+            if let Some(byte) = text.get(position) {
+                states = step(&self.nfa, &states, *byte);
+            }
+        }
+        (0..matched.len()).filter(|index| matched[*index]).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matching() -> Result<(), Error> {
+        let set = RegexSet::new(&["\\d+", "[a-z]+", "x"])?;
+        assert_eq!(set.matching(b"abc123"), [0, 1]);
+        assert_eq!(set.matching(b"--x--"), [1, 2]);
+        assert!(set.matching(b"--").is_empty());
+
+        let set = RegexSet::new(&["a*"])?;
+        assert_eq!(set.matching(b""), [0]);
+
+        assert_eq!(
+            RegexSet::new(&["a", "b{2,1}"]),
+            Err(Error::new(
+                "In {min,max} operator, min should not be greater than max"
+            ))
+        );
+        Ok(())
+    }
+}
@@ -1,13 +1,15 @@
+use super::byteset::ByteSet;
+use super::Options;
 use crate::Error;
-use std::collections::HashSet;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum FirstRegexToken {
     Character(u8),
     MinMax(u8, u8),
     Times(u8),
-    Set(HashSet<u8>),
-    InverseSet(HashSet<u8>),
+    AtLeast(u8),
+    Set(ByteSet),
+    InverseSet(ByteSet),
     Alternation,
     KleenClosure,
     Question,
@@ -15,115 +17,255 @@ pub enum FirstRegexToken {
     Wildcard,
     LParen,
     RParen,
+    CaseInsensitive,
 }
 
 use FirstRegexToken::*;
 
 pub fn scan(regex: &str) -> Result<Vec<FirstRegexToken>, Error> {
+    scan_opts(regex, &Options::default())
+}
+
+pub fn scan_opts(regex: &str, options: &Options) -> Result<Vec<FirstRegexToken>, Error> {
     if !regex.is_ascii() {
         return Err(Error::new("This Regex Engine only supports ASCII"));
     }
-    let mut regex: Vec<u8> = regex.as_bytes().iter().cloned().rev().collect();
-    if regex.len() == 0 {
+    if regex.is_empty() {
         return Err(Error::new("Cannot have an empty regex"));
     }
+    let mut regex = Cursor::new(regex.as_bytes());
     let mut tokens = Vec::new();
-    while let Some(t) = scan_token(&mut regex)? {
-        tokens.push(t);
+    if let Some(flag) = scan_flags(&mut regex)? {
+        tokens.push(flag);
+    }
+    loop {
+        if regex.starts_with(b"\\Q") {
+            scan_quoted(&mut regex, &mut tokens);
+        } else if regex.starts_with(b"(?#") {
+            skip_comment(&mut regex)?;
+        } else if let Some(t) = scan_token(&mut regex, options)? {
+            tokens.push(t);
+        } else {
+            break;
+        }
     }
     Ok(tokens)
 }
 
-fn scan_token(regex: &mut Vec<u8>) -> Result<Option<FirstRegexToken>, Error> {
-    let c = regex.pop();
-    if c.is_none() {
-        return Ok(None);
+/// Reads through the regex front to back
+struct Cursor<'a> {
+    regex: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(regex: &'a [u8]) -> Cursor<'a> {
+        Cursor { regex, index: 0 }
     }
-    let c = c.unwrap();
-    match c {
-        b'\\' => {
-            if let Some(c) = regex.pop() {
-                Ok(Some(Character(get_escape_char(c))))
-            } else {
-                Err(Error::new("Cannot have \\ on end of regex"))
-            }
+
+    fn next_byte(&mut self) -> Option<u8> {
+        let c = self.peek()?;
+        self.index += 1;
+        Some(c)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.regex.get(self.index).cloned()
+    }
+
+    fn starts_with(&self, prefix: &[u8]) -> bool {
+        self.regex[self.index..].starts_with(prefix)
+    }
+
+    fn skip(&mut self, count: usize) {
+        self.index = (self.index + count).min(self.regex.len());
+    }
+
+    fn is_empty(&self) -> bool {
+        self.index >= self.regex.len()
+    }
+}
+
+/// Everything between \Q and \E (or the end of the regex) is a literal
+fn scan_quoted(regex: &mut Cursor, tokens: &mut Vec<FirstRegexToken>) {
+    regex.skip(2);
+    while let Some(c) = regex.next_byte() {
+        if c == b'\\' && regex.peek() == Some(b'E') {
+            regex.skip(1);
+            break;
         }
+        tokens.push(Character(c));
+    }
+}
+
+fn scan_token(regex: &mut Cursor, options: &Options) -> Result<Option<FirstRegexToken>, Error> {
+    let c = match regex.next_byte() {
+        Some(c) => c,
+        None => return Ok(None),
+    };
+    match c {
+        b'\\' => match scan_shorthand(regex, options) {
+            Some(set) => Ok(Some(Set(set))),
+            None => Ok(Some(Character(scan_escape(regex, options)?))),
+        },
         b'|' => Ok(Some(Alternation)),
         b'*' => Ok(Some(KleenClosure)),
         b'?' => Ok(Some(Question)),
         b'+' => Ok(Some(Plus)),
-        b'(' => Ok(Some(LParen)),
-        b')' => Ok(Some(RParen)),
-        b'{' => scan_times(regex),
-        b'[' => {
-            if let Some(c) = regex.pop() {
-                if c == b'^' {
-                    Ok(Some(InverseSet(get_set(regex)?)))
-                } else {
-                    regex.push(c);
-                    Ok(Some(Set(get_set(regex)?)))
-                }
+        b'(' => {
+            if regex.starts_with(b"?>") {
+                // nothing backtracks here so an atomic group is a plain group
+                regex.skip(2);
+                Ok(Some(LParen))
             } else {
-                Err(Error::new("Mismatched []"))
+                Ok(Some(LParen))
             }
         }
+        b')' => Ok(Some(RParen)),
+        b'{' => scan_times(regex),
+        b'[' => match regex.peek() {
+            Some(b'^') => {
+                regex.skip(1);
+                Ok(Some(InverseSet(get_set(regex, options)?)))
+            }
+            Some(_) => Ok(Some(Set(get_set(regex, options)?))),
+            None => Err(Error::new("Mismatched []")),
+        },
         b'.' => Ok(Some(Wildcard)),
         _ => Ok(Some(Character(c))),
     }
 }
 
-fn get_escape_char(letter: u8) -> u8 {
+/// Inline flags are only allowed at the very start of the regex
+fn scan_flags(regex: &mut Cursor) -> Result<Option<FirstRegexToken>, Error> {
+    // (?# is a comment and (?> an atomic group, not flags
+    if !regex.starts_with(b"(?")
+        || regex.starts_with(b"(?#")
+        || regex.starts_with(b"(?>")
+        || regex.regex.len() < 3
+    {
+        return Ok(None);
+    }
+    regex.skip(2);
+    let flag = match regex.next_byte() {
+        Some(b'i') => CaseInsensitive,
+        Some(c) if c != b')' => {
+            return Err(Error::new(&format!("Unknown inline flag (?{})", c as char)))
+        }
+        _ => return Err(Error::new("Inline flag group (?) needs a flag")),
+    };
+    match regex.next_byte() {
+        Some(b')') => Ok(Some(flag)),
+        _ => Err(Error::new("Inline flag group must be closed with )")),
+    }
+}
+
+/// Consumes a (?#...) comment up to and including its closing )
+fn skip_comment(regex: &mut Cursor) -> Result<(), Error> {
+    while let Some(c) = regex.next_byte() {
+        if c == b')' {
+            return Ok(());
+        }
+    }
+    Err(Error::new("Unterminated comment (?#"))
+}
+
+/// Reads the escape after a \, octal escapes like \101 take up to three digits
+fn scan_escape(regex: &mut Cursor, options: &Options) -> Result<u8, Error> {
+    let position = regex.index - 1;
+    let mut number: u32 = 0;
+    let mut digits = 0;
+    while let Some(c @ b'0'..=b'7') = regex.peek() {
+        if digits == 3 {
+            break;
+        }
+        regex.skip(1);
+        number = number * 8 + (c - b'0') as u32;
+        digits += 1;
+    }
+    if digits > 0 {
+        if number > 255 {
+            return Err(Error::new("Octal escapes must be less than 256"));
+        }
+        return Ok(number as u8);
+    }
+
+    // \cA to \cZ are the control characters 1 to 26
+    if regex.peek() == Some(b'c') {
+        regex.skip(1);
+        return match regex.next_byte() {
+            Some(letter) if letter.is_ascii_alphabetic() => Ok(letter.to_ascii_uppercase() ^ 0x40),
+            _ => Err(Error::new("Expected a letter after \\c")),
+        };
+    }
+
+    if let Some(c) = regex.next_byte() {
+        get_escape_char(c, position, options)
+    } else {
+        Err(Error::new("Cannot have \\ on end of regex"))
+    }
+}
+
+/// Unknown escapes are taken literally unless options.strict_escapes is set,
+/// then only punctuation can be escaped that way
+fn get_escape_char(letter: u8, position: usize, options: &Options) -> Result<u8, Error> {
     match letter {
-        b'0' => 0,
-        b'r' => 13,
-        b'n' => 10,
-        b't' => 9,
-        _ => letter,
+        b'r' => Ok(13),
+        b'n' => Ok(10),
+        b't' => Ok(9),
+        _ if options.strict_escapes && letter.is_ascii_alphanumeric() => Err(Error::new(&format!(
+            "Unknown escape sequence \\{} at position {}",
+            letter as char, position
+        ))),
+        _ => Ok(letter),
     }
 }
 
-fn scan_times(regex: &mut Vec<u8>) -> Result<Option<FirstRegexToken>, Error> {
+fn scan_times(regex: &mut Cursor) -> Result<Option<FirstRegexToken>, Error> {
     // get first number in
     let min = get_num(regex)?;
 
-    // check for closing } (times token) or , (min, max token)
-    let c = regex.pop();
-    if c == None {
-        return Err(Error::new("Regex ends without closing {"));
-    }
-    match c.unwrap() {
-        b'}' => return Ok(Some(Times(min))),
-        b',' => (),
+    // check for closing } (times token) or , (min, max or at least token)
+    match regex.next_byte() {
+        None => return Err(Error::new("Regex ends without closing {")),
+        Some(b'}') => return Ok(Some(Times(min))),
+        Some(b',') => (),
         _ => return Err(Error::new("Illegal character in brackets")),
     }
 
+    // {min,} has no max
+    if regex.peek() == Some(b'}') {
+        regex.skip(1);
+        return Ok(Some(AtLeast(min)));
+    }
+
     // get max for min max
     let max = get_num(regex)?;
 
     // make sure it has closing }
-    if let Some(c) = regex.pop() {
-        if c == b'}' {
-            Ok(Some(MinMax(min, max)))
-        } else {
-            Err(Error::new("Mismatched {}"))
-        }
-    } else {
-        Err(Error::new("Regex ends without closing {"))
+    match regex.next_byte() {
+        Some(b'}') => Ok(Some(MinMax(min, max))),
+        Some(_) => Err(Error::new("Mismatched {}")),
+        None => Err(Error::new("Regex ends without closing {")),
     }
 }
 
-fn get_num(regex: &mut Vec<u8>) -> Result<u8, Error> {
+fn get_num(regex: &mut Cursor) -> Result<u8, Error> {
     if regex.is_empty() {
         return Err(Error::new("Mismatched {"));
     }
 
     let mut number: u64 = 0;
-    while let Some(c) = regex.pop() {
-        if c < 0x30 || c > 0x39 {
-            regex.push(c);
+    while let Some(c) = regex.peek() {
+        if !c.is_ascii_digit() {
             break;
         }
+        regex.skip(1);
         number = (number * 10) + ((c & 0x0f) as u64);
+        // stop before the number can overflow
+        if number > 255 {
+            break;
+        }
     }
 
     if number > 255 {
@@ -132,54 +274,100 @@ fn get_num(regex: &mut Vec<u8>) -> Result<u8, Error> {
     Ok(number as u8)
 }
 
-fn get_set(regex: &mut Vec<u8>) -> Result<HashSet<u8>, Error> {
-    let mut set = HashSet::new();
-    while let Some(c) = regex.pop() {
-        match c {
-            b'\\' => {
-                if let Some(c) = regex.pop() {
-                    regex.push(get_escape_char(c));
-                } else {
-                    return Err(Error::new("Cannot have \\ on end of regex"));
-                }
+fn get_set(regex: &mut Cursor, options: &Options) -> Result<ByteSet, Error> {
+    let mut set = ByteSet::new();
+    while let Some(c) = regex.next_byte() {
+        if c == b'\\' {
+            if let Some(class) = regex.peek().and_then(shorthand_class) {
+                regex.skip(1);
+                set = set.union(&class);
+                continue;
             }
+        }
+        let first = match c {
+            b'\\' => scan_set_escape(regex, options)?,
             b']' => break,
-            _ => {
-                let first = c;
-                if let Some(c) = regex.pop() {
-                    match c {
-                        b']' => {
-                            set.insert(first);
-                            break;
-                        }
-                        b'-' => {
-                            if let Some(c) = regex.pop() {
-                                for i in first..(c + 1) {
-                                    set.insert(i);
-                                }
-                            } else {
-                                return Err(Error::new("Mismatched []"));
-                            }
-                        }
-                        _ => {
-                            set.insert(first);
-                            regex.push(c);
-                        }
+            _ => c,
+        };
+        match regex.next_byte() {
+            Some(b']') => {
+                set.insert(first);
+                break;
+            }
+            Some(b'-') => {
+                if let Some(last) = regex.next_byte() {
+                    let last = match last {
+                        b'\\' => scan_set_escape(regex, options)?,
+                        _ => last,
+                    };
+                    for i in first..=last {
+                        set.insert(i);
                     }
                 } else {
                     return Err(Error::new("Mismatched []"));
                 }
             }
+            Some(_) => {
+                set.insert(first);
+                regex.index -= 1;
+            }
+            None => return Err(Error::new("Mismatched []")),
         }
     }
     Ok(set)
 }
 
+/// Same as scan_escape except \b is a backspace inside a set
+fn scan_set_escape(regex: &mut Cursor, options: &Options) -> Result<u8, Error> {
+    if regex.peek() == Some(b'b') {
+        regex.skip(1);
+        return Ok(8);
+    }
+    scan_escape(regex, options)
+}
+
+/// \d, \s and \w outside a set, \D, \S and \W are every byte up to
+/// options.max_byte that the lowercase class doesn't have
+fn scan_shorthand(regex: &mut Cursor, options: &Options) -> Option<ByteSet> {
+    let letter = regex.peek()?;
+    let set = match shorthand_class(letter) {
+        Some(set) => set,
+        None if letter.is_ascii_uppercase() => {
+            let class = shorthand_class(letter.to_ascii_lowercase())?;
+            let mut set = ByteSet::new();
+            for byte in class
+                .invert()
+                .iter()
+                .filter(|byte| *byte <= options.max_byte)
+            {
+                set.insert(byte);
+            }
+            set
+        }
+        None => return None,
+    };
+    regex.skip(1);
+    Some(set)
+}
+
+/// The bytes \d, \s and \w stand for
+fn shorthand_class(letter: u8) -> Option<ByteSet> {
+    let mut set = ByteSet::new();
+    match letter {
+        b'd' => (b'0'..=b'9').for_each(|c| set.insert(c)),
+        b's' => b" \t\n\r\x0b\x0c".iter().for_each(|c| set.insert(*c)),
+        b'w' => (0..128)
+            .filter(|c: &u8| c.is_ascii_alphanumeric() || *c == b'_')
+            .for_each(|c| set.insert(c)),
+        _ => return None,
+    }
+    Some(set)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::Error;
-    use rand::Rng;
 
     #[test]
     fn basic() -> Result<(), Error> {
@@ -211,9 +399,9 @@ mod test {
         match token {
             Set(s) => {
                 assert_eq!(s.len(), 3);
-                assert!(s.contains(&b'a'));
-                assert!(s.contains(&b'b'));
-                assert!(s.contains(&b'c'));
+                assert!(s.contains(b'a'));
+                assert!(s.contains(b'b'));
+                assert!(s.contains(b'c'));
             }
             _ => panic!("Unexpected token"),
         }
@@ -225,9 +413,9 @@ mod test {
         match token {
             InverseSet(s) => {
                 assert_eq!(s.len(), 3);
-                assert!(s.contains(&b'a'));
-                assert!(s.contains(&b'b'));
-                assert!(s.contains(&b'c'));
+                assert!(s.contains(b'a'));
+                assert!(s.contains(b'b'));
+                assert!(s.contains(b'c'));
             }
             _ => panic!("Unexpected token"),
         }
@@ -235,6 +423,213 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn flags() -> Result<(), Error> {
+        let regex = r"(?i)a";
+        let tokens = scan(regex)?;
+        assert_eq!(tokens, [CaseInsensitive, Character(b'a')]);
+
+        let regex = r"(?z)abc";
+        assert_eq!(scan(regex), Err(Error::new("Unknown inline flag (?z)")));
+
+        let regex = r"(?i";
+        assert_eq!(
+            scan(regex),
+            Err(Error::new("Inline flag group must be closed with )"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn comments() -> Result<(), Error> {
+        let regex = r"a(?#this is a)b";
+        let tokens = scan(regex)?;
+        assert_eq!(tokens, [Character(b'a'), Character(b'b')]);
+        assert_eq!(crate::regex::get_nfa(regex)?, crate::regex::get_nfa("ab")?);
+
+        let regex = r"(?#start)a";
+        let tokens = scan(regex)?;
+        assert_eq!(tokens, [Character(b'a')]);
+
+        let regex = r"x(?#c)\Qa.b\E";
+        let tokens = scan(regex)?;
+        assert_eq!(
+            tokens,
+            [
+                Character(b'x'),
+                Character(b'a'),
+                Character(b'.'),
+                Character(b'b')
+            ]
+        );
+
+        let regex = r"a(?#never closed";
+        assert_eq!(scan(regex), Err(Error::new("Unterminated comment (?#")));
+        Ok(())
+    }
+
+    #[test]
+    fn quoted() -> Result<(), Error> {
+        let regex = r"\Qa+b\E";
+        let tokens = scan(regex)?;
+        assert_eq!(tokens, [Character(b'a'), Character(b'+'), Character(b'b')]);
+        assert_eq!(
+            crate::regex::get_nfa(regex)?,
+            crate::regex::get_nfa(r"a\+b")?
+        );
+
+        let regex = r"x\Q(*\E+";
+        let tokens = scan(regex)?;
+        assert_eq!(
+            tokens,
+            [Character(b'x'), Character(b'('), Character(b'*'), Plus]
+        );
+
+        let regex = r"\Q.*";
+        let tokens = scan(regex)?;
+        assert_eq!(tokens, [Character(b'.'), Character(b'*')]);
+        Ok(())
+    }
+
+    #[test]
+    fn strict_escapes() -> Result<(), Error> {
+        let regex = r"a\q";
+        let tokens = scan(regex)?;
+        assert_eq!(tokens, [Character(b'a'), Character(b'q')]);
+
+        let options = Options {
+            strict_escapes: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            scan_opts(regex, &options),
+            Err(Error::new("Unknown escape sequence \\q at position 1"))
+        );
+        assert_eq!(
+            scan_opts(r"[ab\q]", &options),
+            Err(Error::new("Unknown escape sequence \\q at position 3"))
+        );
+
+        let regex = r"\n\t\.";
+        let expected = [Character(b'\n'), Character(b'\t'), Character(b'.')];
+        assert_eq!(scan(regex)?, expected);
+        assert_eq!(scan_opts(regex, &options)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn shorthand_in_set() -> Result<(), Error> {
+        let nfa = crate::regex::get_nfa(r"[\d.]")?;
+        for c in b"0123456789.".iter() {
+            assert!(crate::regex::simulate::matches(&nfa, &[*c]));
+        }
+        assert!(!crate::regex::simulate::matches(&nfa, b"a"));
+        assert!(!crate::regex::simulate::matches(&nfa, b"\\"));
+
+        let tokens = scan(r"[\s\w]")?;
+        let set = match tokens[..] {
+            [Set(set)] => set,
+            _ => panic!("Unexpected tokens"),
+        };
+        assert_eq!(set.len(), 6 + 63);
+        assert!(set.contains(b'\t'));
+        assert!(set.contains(b'_'));
+        assert!(!set.contains(b'-'));
+
+        let tokens = scan(r"[^\d]")?;
+        assert_eq!(tokens, scan("[^0-9]")?);
+        Ok(())
+    }
+
+    #[test]
+    fn shorthand() -> Result<(), Error> {
+        use crate::regex::simulate::matches;
+        let nfa = crate::regex::get_nfa(r"\d+")?;
+        assert!(matches(&nfa, b"123"));
+        assert!(!matches(&nfa, b"ddd"));
+        assert_eq!(scan(r"\d")?, scan(r"[\d]")?);
+
+        let nfa = crate::regex::get_nfa(r"\w")?;
+        assert!(matches(&nfa, b"_"));
+        assert!(matches(&nfa, b"Z"));
+        assert!(!matches(&nfa, b"-"));
+
+        let nfa = crate::regex::get_nfa(r"a\sb")?;
+        assert!(matches(&nfa, b"a b"));
+        assert!(matches(&nfa, b"a\tb"));
+        assert!(!matches(&nfa, b"asb"));
+
+        let nfa = crate::regex::get_nfa(r"\D\S\W")?;
+        assert!(matches(&nfa, b"\n_-"));
+        assert!(!matches(&nfa, b"1a-"));
+        assert!(!matches(&nfa, b"a a"));
+        assert!(!matches(&nfa, b"aa_"));
+
+        let strict = Options {
+            strict_escapes: true,
+            ..Options::default()
+        };
+        assert_eq!(scan_opts(r"\d", &strict)?, scan(r"\d")?);
+        Ok(())
+    }
+
+    #[test]
+    fn backspace() -> Result<(), Error> {
+        let nfa = crate::regex::get_nfa(r"[\b]")?;
+        assert!(crate::regex::simulate::matches(&nfa, b"\x08"));
+        assert!(!crate::regex::simulate::matches(&nfa, b"b"));
+
+        let mut set = ByteSet::new();
+        set.insert(8);
+        set.insert(b'a');
+        assert_eq!(scan(r"[a\b]")?, [Set(set)]);
+
+        // there are no assertions, so outside a set \b is just b
+        assert_eq!(scan(r"\b")?, [Character(b'b')]);
+        Ok(())
+    }
+
+    #[test]
+    fn control() -> Result<(), Error> {
+        assert_eq!(scan(r"\cI")?, [Character(9)]);
+        assert_eq!(scan(r"\ca\cZ")?, [Character(1), Character(26)]);
+        assert_eq!(scan(r"\c5"), Err(Error::new("Expected a letter after \\c")));
+        assert_eq!(scan(r"\c"), Err(Error::new("Expected a letter after \\c")));
+
+        let mut set = ByteSet::new();
+        set.insert(b'\n');
+        assert_eq!(scan(r"[\cJ]")?, [Set(set)]);
+        Ok(())
+    }
+
+    #[test]
+    fn atomic_group() -> Result<(), Error> {
+        assert_eq!(scan("(?>ab)c")?, scan("(ab)c")?);
+        assert_eq!(scan("x(?>a|b)")?, scan("x(a|b)")?);
+        let nfa = crate::regex::get_nfa("(?>ab)c")?;
+        assert!(crate::regex::simulate::matches(&nfa, b"abc"));
+        Ok(())
+    }
+
+    #[test]
+    fn octal() -> Result<(), Error> {
+        assert_eq!(scan(r"\101")?, [Character(b'A')]);
+        assert_eq!(scan(r"\0")?, [Character(0)]);
+        assert_eq!(scan(r"\012")?, [Character(b'\n')]);
+        assert_eq!(scan(r"\1011")?, [Character(b'A'), Character(b'1')]);
+        assert_eq!(scan(r"\08")?, [Character(0), Character(b'8')]);
+        assert_eq!(
+            scan(r"\777"),
+            Err(Error::new("Octal escapes must be less than 256"))
+        );
+
+        let mut set = ByteSet::new();
+        set.insert(b'A');
+        set.insert(b'B');
+        assert_eq!(scan(r"[\101-\102]")?, [Set(set)]);
+        Ok(())
+    }
+
     #[test]
     fn brakcets() -> Result<(), Error> {
         let regex = r"a{3}";
@@ -244,20 +639,40 @@ mod test {
         let regex = r"a{3,5}";
         let tokens = scan(regex)?;
         assert_eq!(tokens, [Character(b'a'), MinMax(3, 5)]);
+
+        let regex = r"a{,5}";
+        let tokens = scan(regex)?;
+        assert_eq!(tokens, [Character(b'a'), MinMax(0, 5)]);
+
+        let regex = r"a{3,}";
+        let tokens = scan(regex)?;
+        assert_eq!(tokens, [Character(b'a'), AtLeast(3)]);
+        Ok(())
+    }
+
+    #[test]
+    fn large() -> Result<(), Error> {
+        let piece = r"(a|b)*[c-e\]]{2,4}[^\n]x{3}\Qy*\E.+(?#z)\\";
+        let tokens = scan(piece)?;
+        let regex = piece.repeat(1000);
+        let expected: Vec<FirstRegexToken> = tokens
+            .iter()
+            .cycle()
+            .take(tokens.len() * 1000)
+            .cloned()
+            .collect();
+        assert_eq!(scan(&regex)?, expected);
+
+        let mut set = ByteSet::new();
+        set.insert(b']');
+        assert_eq!(scan(r"[\]]")?, [Set(set)]);
         Ok(())
     }
 
     #[test]
-    #[allow(unused_must_use)]
     fn monkey() {
-        let mut rng = rand::thread_rng();
-        for _ in 0..10000 {
-            let length = rng.gen_range(0, 16);
-            let mut regex = String::new();
-            for _ in 0..length {
-                regex.push(rng.gen_range(32, 127) as u8 as char);
-            }
-            scan(&regex); // result needs to be unused
-        }
+        crate::regex::monkey::run(10000, |regex| {
+            let _ = scan(regex);
+        });
     }
 }
@@ -0,0 +1,190 @@
+use super::byteset::ByteSet;
+use super::nfa::{validate, Transition, NFA};
+use crate::Error;
+
+// Layout, every number is a LEB128 varint:
+//   node count, then per node a tag byte followed by
+//   0 Epsilon:   target count, targets
+//   1 Character: the byte, target
+//   2 Set:       32 byte bitmap (bit i of byte i / 8 is byte i), target
+
+/// Compact encoding of an NFA that from_bytes can load back
+pub fn to_bytes(nfa: &NFA) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write_number(&mut bytes, nfa.len());
+    for transition in nfa {
+        match transition {
+            Transition::Epsilon(to) => {
+                bytes.push(0);
+                write_number(&mut bytes, to.len());
+                for pos in to {
+                    write_number(&mut bytes, *pos);
+                }
+            }
+            Transition::Character(c, to) => {
+                bytes.push(1);
+                bytes.push(*c);
+                write_number(&mut bytes, *to);
+            }
+            Transition::Set(set, to) => {
+                bytes.push(2);
+                let mut bitmap = [0u8; 32];
+                for byte in set.iter() {
+                    bitmap[(byte / 8) as usize] |= 1 << (byte % 8);
+                }
+                bytes.extend_from_slice(&bitmap);
+                write_number(&mut bytes, *to);
+            }
+        }
+    }
+    bytes
+}
+
+/// Loads an NFA written by to_bytes, checking it's complete and valid
+pub fn from_bytes(bytes: &[u8]) -> Result<NFA, Error> {
+    let mut reader = Reader { bytes, index: 0 };
+    let len = reader.number()?;
+    let mut nfa = Vec::new();
+    for _ in 0..len {
+        let transition = match reader.byte()? {
+            0 => {
+                let count = reader.number()?;
+                let mut to = Vec::new();
+                for _ in 0..count {
+                    to.push(reader.number()?);
+                }
+                Transition::Epsilon(to)
+            }
+            1 => {
+                let c = reader.byte()?;
+                Transition::Character(c, reader.number()?)
+            }
+            2 => {
+                let mut set = ByteSet::new();
+                for chunk in 0..32 {
+                    let bits = reader.byte()?;
+                    for bit in 0..8 {
+                        if bits & (1 << bit) != 0 {
+                            set.insert(chunk * 8 + bit);
+                        }
+                    }
+                }
+                Transition::Set(set, reader.number()?)
+            }
+            _ => return Err(Error::new("Serialized NFA has an unknown transition")),
+        };
+        nfa.push(transition);
+    }
+    if reader.index != bytes.len() {
+        return Err(Error::new("Serialized NFA has trailing bytes"));
+    }
+    validate(&nfa)?;
+    Ok(nfa)
+}
+
+fn write_number(bytes: &mut Vec<u8>, mut number: usize) {
+    while number >= 0x80 {
+        bytes.push((number as u8 & 0x7f) | 0x80);
+        number >>= 7;
+    }
+    bytes.push(number as u8);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, Error> {
+        let byte = self.bytes.get(self.index).cloned();
+        self.index += 1;
+        byte.ok_or_else(|| Error::new("Serialized NFA is truncated"))
+    }
+
+    fn number(&mut self) -> Result<usize, Error> {
+        let mut number: usize = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            let bits = (byte & 0x7f) as usize;
+            // the last byte that fits can only have its lowest bits set
+            if shift >= usize::MAX.count_ones() || (bits << shift) >> shift != bits {
+                return Err(Error::new("Serialized NFA has a number that is too large"));
+            }
+            number |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(number);
+            }
+            shift += 7;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() -> Result<(), Error> {
+        let patterns = ["a", "a(b|c)*", "[^aeiou]+x?", "(ab){2,5}", "[\\000-\\377]"];
+        for pattern in patterns.iter() {
+            let nfa = crate::regex::get_nfa(pattern)?;
+            assert_eq!(from_bytes(&to_bytes(&nfa))?, nfa);
+        }
+
+        let mut big = vec![Transition::Epsilon(vec![300]); 300];
+        big.push(Transition::Epsilon(Vec::new()));
+        assert_eq!(from_bytes(&to_bytes(&big))?, big);
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt() -> Result<(), Error> {
+        let bytes = to_bytes(&crate::regex::get_nfa("ab")?);
+        assert_eq!(
+            from_bytes(&bytes[..bytes.len() - 1]),
+            Err(Error::new("Serialized NFA is truncated"))
+        );
+        assert_eq!(
+            from_bytes(&[]),
+            Err(Error::new("Serialized NFA is truncated"))
+        );
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(
+            from_bytes(&trailing),
+            Err(Error::new("Serialized NFA has trailing bytes"))
+        );
+
+        let mut unknown = bytes.clone();
+        unknown[1] = 7;
+        assert_eq!(
+            from_bytes(&unknown),
+            Err(Error::new("Serialized NFA has an unknown transition"))
+        );
+
+        let mut overflowing = vec![0xff; 9];
+        overflowing.push(0x02);
+        assert_eq!(
+            from_bytes(&overflowing),
+            Err(Error::new("Serialized NFA has a number that is too large"))
+        );
+        let mut overlong = vec![0x80; 10];
+        overlong.push(0x00);
+        assert_eq!(
+            from_bytes(&overlong),
+            Err(Error::new("Serialized NFA has a number that is too large"))
+        );
+
+        // a single character node pointing past the end
+        assert_eq!(
+            from_bytes(&[2, 1, b'a', 9, 0, 0]),
+            Err(Error::new(
+                "NFA has a transition to a node that doesn't exist"
+            ))
+        );
+        Ok(())
+    }
+}
@@ -1,13 +1,18 @@
+use super::byteset::ByteSet;
 use super::scan::FirstRegexToken;
+use super::Options;
 use crate::Error;
-use std::collections::HashSet;
 use Token::*;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Token {
     Character(u8),
+    Set(ByteSet),
     MinMax(u8, u8),
     Times(u8),
+    AtLeast(u8),
+    /// Stands in for a missing alternation operand
+    Empty,
     Concat,
     Alternation,
     KleenClosure,
@@ -19,65 +24,79 @@ pub enum Token {
 
 /// Simpilifies Set, InversSet, and Wildcard and adds Concat operator
 pub fn simpilfy(regex: &[FirstRegexToken]) -> Result<Vec<Token>, Error> {
+    simpilfy_opts(regex, &Options::default())
+}
+
+pub fn simpilfy_opts(regex: &[FirstRegexToken], options: &Options) -> Result<Vec<Token>, Error> {
     let mut tokens = Vec::new();
     let mut regex: Vec<FirstRegexToken> = regex.iter().cloned().rev().collect();
+    let mut case_insensitive = options.case_insensitive;
 
     // Simpilfy pass
     while let Some(t) = regex.pop() {
         match t {
             FirstRegexToken::Set(hs) => {
+                let hs = if case_insensitive { fold_case(hs) } else { hs };
                 if hs.is_empty() {
                     return Err(Error::new("Cannot have an empty set []"));
                 }
-                tokens.push(LParen);
-                for byte in hs {
-                    tokens.push(Character(byte));
-                    tokens.push(Alternation);
+                // a whole range stays one set instead of an alternation per byte
+                if hs.len() == 1 {
+                    tokens.extend(hs.iter().map(Character));
+                } else {
+                    tokens.push(Set(hs));
                 }
-                tokens.pop();
-                tokens.push(RParen);
             }
             FirstRegexToken::InverseSet(set) => {
-                let mut new_set = HashSet::new();
-                // sorry ascii only
-                for i in 0..127 {
-                    if !set.contains(&i) {
-                        new_set.insert(i);
-                    }
+                let mut set = if case_insensitive {
+                    fold_case(set)
+                } else {
+                    set
+                };
+                if !options.negated_sets_match_newline {
+                    set.insert(b'\n');
+                }
+                let mut hs = ByteSet::new();
+                for byte in set.invert().iter().filter(|byte| *byte <= options.max_byte) {
+                    hs.insert(byte);
                 }
-                let hs = new_set;
                 if hs.is_empty() {
                     return Err(Error::new("Cannot have an empty set []"));
                 }
-                tokens.push(LParen);
-                for byte in hs {
-                    tokens.push(Character(byte));
-                    tokens.push(Alternation);
-                }
-                tokens.pop();
-                tokens.push(RParen);
+                tokens.push(Set(hs));
             }
             FirstRegexToken::Wildcard => {
-                tokens.push(LParen);
-                for byte in 0..127 {
-                    tokens.push(Character(byte));
-                    tokens.push(Alternation);
+                let mut hs = ByteSet::new();
+                for byte in 0..=options.max_byte {
+                    hs.insert(byte);
                 }
-                tokens.pop();
+                tokens.push(Set(hs));
+            }
+            FirstRegexToken::Character(c) if case_insensitive && c.is_ascii_alphabetic() => {
+                tokens.push(LParen);
+                tokens.push(Character(c.to_ascii_lowercase()));
+                tokens.push(Alternation);
+                tokens.push(Character(c.to_ascii_uppercase()));
                 tokens.push(RParen);
             }
             FirstRegexToken::Character(c) => tokens.push(Character(c)),
             FirstRegexToken::MinMax(min, max) => tokens.push(MinMax(min, max)),
             FirstRegexToken::Times(min) => tokens.push(Times(min)),
+            FirstRegexToken::AtLeast(min) => tokens.push(AtLeast(min)),
             FirstRegexToken::Alternation => tokens.push(Alternation),
             FirstRegexToken::KleenClosure => tokens.push(KleenClosure),
             FirstRegexToken::Question => tokens.push(Question),
             FirstRegexToken::Plus => tokens.push(Plus),
             FirstRegexToken::LParen => tokens.push(LParen),
             FirstRegexToken::RParen => tokens.push(RParen),
+            FirstRegexToken::CaseInsensitive => case_insensitive = true,
         }
     }
 
+    if options.allow_empty_alternation {
+        add_empty_operands(&mut tokens);
+    }
+
     // add concatination pass
     let mut index = 0;
     while index + 1 < tokens.len() {
@@ -86,8 +105,11 @@ pub fn simpilfy(regex: &[FirstRegexToken]) -> Result<Vec<Token>, Error> {
 
         match first {
             Character(_) => first_is_normal(&mut tokens, second, index + 1),
+            Set(_) => first_is_normal(&mut tokens, second, index + 1),
+            Empty => first_is_normal(&mut tokens, second, index + 1),
             MinMax(_, _) => first_is_normal(&mut tokens, second, index + 1),
             Times(_) => first_is_normal(&mut tokens, second, index + 1),
+            AtLeast(_) => first_is_normal(&mut tokens, second, index + 1),
             KleenClosure => first_is_normal(&mut tokens, second, index + 1),
             Question => first_is_normal(&mut tokens, second, index + 1),
             Plus => first_is_normal(&mut tokens, second, index + 1),
@@ -100,9 +122,41 @@ pub fn simpilfy(regex: &[FirstRegexToken]) -> Result<Vec<Token>, Error> {
     Ok(tokens)
 }
 
+/// Puts an Empty token wherever an alternation is missing an operand, like (a|)
+fn add_empty_operands(tokens: &mut Vec<Token>) {
+    let mut index = 0;
+    while index <= tokens.len() {
+        let before = if index == 0 {
+            None
+        } else {
+            tokens.get(index - 1)
+        };
+        let after = tokens.get(index);
+        let opens = matches!(before, None | Some(LParen) | Some(Alternation));
+        let closes = matches!(after, None | Some(RParen) | Some(Alternation));
+        let alternation = before == Some(&Alternation) || after == Some(&Alternation);
+        if opens && closes && alternation {
+            tokens.insert(index, Empty);
+            index += 1;
+        }
+        index += 1;
+    }
+}
+
+fn fold_case(set: ByteSet) -> ByteSet {
+    let mut folded = ByteSet::new();
+    for byte in set.iter() {
+        folded.insert(byte.to_ascii_lowercase());
+        folded.insert(byte.to_ascii_uppercase());
+    }
+    folded
+}
+
 fn first_is_normal(tokens: &mut Vec<Token>, second: Token, index: usize) {
     match second {
         Character(_) => tokens.insert(index, Concat),
+        Set(_) => tokens.insert(index, Concat),
+        Empty => tokens.insert(index, Concat),
         LParen => tokens.insert(index, Concat),
         _ => (),
     }
@@ -111,7 +165,6 @@ fn first_is_normal(tokens: &mut Vec<Token>, second: Token, index: usize) {
 #[cfg(test)]
 mod test {
     use super::*;
-    use rand::Rng;
 
     #[test]
     fn basic() -> Result<(), Error> {
@@ -127,23 +180,84 @@ mod test {
         let regex = "[a-c]";
         let regex = super::super::scan::scan(regex)?;
         let tokens = simpilfy(&regex[..])?;
-        assert_eq!(tokens.len(), 7);
-        assert_eq!(tokens[0], LParen);
-        assert_eq!(tokens[6], RParen);
-        assert_eq!(tokens[2], Alternation);
-        assert_eq!(tokens[4], Alternation);
-        assert!(tokens.contains(&Character(b'a')));
-        assert!(tokens.contains(&Character(b'b')));
-        assert!(tokens.contains(&Character(b'c')));
+        let mut set = ByteSet::new();
+        set.insert(b'a');
+        set.insert(b'b');
+        set.insert(b'c');
+        assert_eq!(tokens, [Set(set)]);
+
+        let regex = "[a]";
+        let regex = super::super::scan::scan(regex)?;
+        let tokens = simpilfy(&regex[..])?;
+        assert_eq!(tokens, [Character(b'a')]);
 
         let regex = "[^a-c]";
         let regex = super::super::scan::scan(regex)?;
         let tokens = simpilfy(&regex[..])?;
-        assert!(tokens.len() > 100);
-        assert!(!tokens.contains(&Character(b'a')));
-        assert!(!tokens.contains(&Character(b'b')));
-        assert!(!tokens.contains(&Character(b'c')));
+        assert_eq!(tokens.len(), 1);
+        match tokens[0] {
+            Set(set) => {
+                assert_eq!(set.len(), 125);
+                assert!(!set.contains(b'a'));
+                assert!(!set.contains(b'b'));
+                assert!(!set.contains(b'c'));
+                assert!(set.contains(b'd'));
+            }
+            _ => panic!("Unexpected token"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn inverse_set_newline() -> Result<(), Error> {
+        let regex = "[^a]";
+        let regex = super::super::scan::scan(regex)?;
+        let tokens = simpilfy(&regex[..])?;
+        assert_eq!(tokens.len(), 1);
+        let set = match tokens[0] {
+            Set(set) => set,
+            _ => panic!("Unexpected token"),
+        };
+        assert!(set.contains(b'\n'));
+
+        let options = Options {
+            negated_sets_match_newline: false,
+            ..Options::default()
+        };
+        let tokens = simpilfy_opts(&regex[..], &options)?;
+        let set = match tokens[0] {
+            Set(set) => set,
+            _ => panic!("Unexpected token"),
+        };
+        assert!(!set.contains(b'\n'));
+        assert!(!set.contains(b'a'));
+        assert!(set.contains(b'b'));
+        Ok(())
+    }
+
+    #[test]
+    fn max_byte() -> Result<(), Error> {
+        let regex = super::super::scan::scan(".")?;
+        let tokens = simpilfy(&regex[..])?;
+        let mut ascii = ByteSet::new();
+        for byte in 0..128 {
+            ascii.insert(byte);
+        }
+        assert_eq!(tokens, [Set(ascii)]);
 
+        let options = Options {
+            max_byte: 255,
+            ..Options::default()
+        };
+        let nfa = crate::regex::get_nfa_opts(".", &options)?;
+        assert!(crate::regex::simulate::matches(&nfa, &[0xff]));
+        let nfa = crate::regex::get_nfa_opts("[^a]", &options)?;
+        assert!(crate::regex::simulate::matches(&nfa, &[0xff]));
+        assert!(!crate::regex::simulate::matches(
+            &crate::regex::get_nfa(".")?,
+            &[0xff]
+        ));
         Ok(())
     }
 
@@ -175,18 +289,34 @@ mod test {
     }
 
     #[test]
-    #[allow(unused_must_use)]
+    fn case_insensitive() -> Result<(), Error> {
+        let regex = "(?i)abc";
+        let regex = super::super::scan::scan(regex)?;
+        let tokens = simpilfy(&regex[..])?;
+        let expected = "(a|A)(b|B)(c|C)";
+        let expected = super::super::scan::scan(expected)?;
+        let expected = simpilfy(&expected[..])?;
+        assert_eq!(tokens, expected);
+
+        let regex = "(?i)[^a]";
+        let regex = super::super::scan::scan(regex)?;
+        let tokens = simpilfy(&regex[..])?;
+        let set = match tokens[0] {
+            Set(set) => set,
+            _ => panic!("Unexpected token"),
+        };
+        assert!(!set.contains(b'a'));
+        assert!(!set.contains(b'A'));
+        assert!(set.contains(b'b'));
+        Ok(())
+    }
+
+    #[test]
     fn monkey() {
-        let mut rng = rand::thread_rng();
-        for _ in 0..10000 {
-            let length = rng.gen_range(0, 16);
-            let mut regex = String::new();
-            for _ in 0..length {
-                regex.push(rng.gen_range(32, 127) as u8 as char);
-            }
-            if let Ok(regex) = super::super::scan::scan(&regex) {
-                simpilfy(&regex[..]); // result needs to be unused
+        crate::regex::monkey::run(10000, |regex| {
+            if let Ok(regex) = super::super::scan::scan(regex) {
+                let _ = simpilfy(&regex[..]);
             }
-        }
+        });
     }
 }
@@ -0,0 +1,315 @@
+use super::nfa::used_bytes;
+use super::nfa::Transition::*;
+use super::nfa::NFA;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+/// Adds every node reachable through epsilon transitions to states
+pub fn epsilon_closure(nfa: &NFA, states: &mut HashSet<usize>) {
+    let mut stack: Vec<usize> = states.iter().cloned().collect();
+    while let Some(state) = stack.pop() {
+        if let Epsilon(to) = &nfa[state] {
+            for next in to {
+                if states.insert(*next) {
+                    stack.push(*next);
+                }
+            }
+        }
+    }
+}
+
+/// The set of nodes the NFA could be in after reading all of input
+pub fn simulate(nfa: &NFA, input: &[u8]) -> HashSet<usize> {
+    let mut states = HashSet::new();
+    states.insert(0);
+    epsilon_closure(nfa, &mut states);
+    for byte in input {
+        states = step(nfa, &states, *byte);
+        if states.is_empty() {
+            break;
+        }
+    }
+    states
+}
+
+/// Reads one byte from every node in states and follows epsilons after
+pub fn step(nfa: &NFA, states: &HashSet<usize>, byte: u8) -> HashSet<usize> {
+    let mut next = HashSet::new();
+    for state in states {
+        match nfa[*state] {
+            Character(c, to) if c == byte => {
+                next.insert(to);
+            }
+            Set(set, to) if set.contains(byte) => {
+                next.insert(to);
+            }
+            _ => (),
+        }
+    }
+    epsilon_closure(nfa, &mut next);
+    next
+}
+
+/// The active nodes after each byte of input, for seeing where matching went wrong
+pub fn trace(nfa: &NFA, input: &[u8]) -> Vec<HashSet<usize>> {
+    let mut states = simulate(nfa, b"");
+    let mut steps = Vec::with_capacity(input.len());
+    for byte in input {
+        states = step(nfa, &states, *byte);
+        steps.push(states.clone());
+    }
+    steps
+}
+
+/// Whether the NFA accepts all of input
+pub fn matches(nfa: &NFA, input: &[u8]) -> bool {
+    !nfa.is_empty() && simulate(nfa, input).contains(&(nfa.len() - 1))
+}
+
+/// Up to max accepted strings, shortest first and in byte order for the same length.
+/// States that can't reach the finish are dropped so every prefix searched can
+/// still be accepted, otherwise a dead loop would keep the search going forever.
+/// Prefixes of the same length that end in the same states accept the same
+/// suffixes, so only the first max of them can lead to a sample and the rest
+/// are dropped too
+pub fn sample_strings(nfa: &NFA, max: usize) -> Vec<Vec<u8>> {
+    if nfa.is_empty() {
+        return Vec::new();
+    }
+    let alphabet: Vec<u8> = used_bytes(nfa).iter().collect();
+    let live = reaches_finish(nfa);
+    let prune = |states: HashSet<usize>| -> HashSet<usize> {
+        states.into_iter().filter(|state| live[*state]).collect()
+    };
+    let mut samples = Vec::new();
+    let mut level = vec![(Vec::new(), prune(simulate(nfa, b"")))];
+    while !level.is_empty() {
+        let mut next_level = Vec::new();
+        let mut seen: HashMap<Vec<usize>, usize> = HashMap::new();
+        for (prefix, states) in level {
+            if samples.len() >= max {
+                return samples;
+            }
+            if states.contains(&(nfa.len() - 1)) {
+                samples.push(prefix.clone());
+            }
+            for byte in alphabet.iter() {
+                let next = prune(step(nfa, &states, *byte));
+                if next.is_empty() {
+                    continue;
+                }
+                let mut key: Vec<usize> = next.iter().cloned().collect();
+                key.sort_unstable();
+                let count = seen.entry(key).or_insert(0);
+                if *count < max {
+                    *count += 1;
+                    let mut longer = prefix.clone();
+                    longer.push(*byte);
+                    next_level.push((longer, next));
+                }
+            }
+        }
+        level = next_level;
+    }
+    samples
+}
+
+/// For each node, whether some path of transitions leads from it to the finish
+fn reaches_finish(nfa: &NFA) -> Vec<bool> {
+    let mut from: Vec<Vec<usize>> = vec![Vec::new(); nfa.len()];
+    for (node, transition) in nfa.iter().enumerate() {
+        match transition {
+            Epsilon(to) => {
+                for next in to {
+                    from[*next].push(node);
+                }
+            }
+            Character(_, to) | Set(_, to) => from[*to].push(node),
+        }
+    }
+    let mut live = vec![false; nfa.len()];
+    let mut stack = vec![nfa.len() - 1];
+    while let Some(node) = stack.pop() {
+        if !live[node] {
+            live[node] = true;
+            stack.extend(from[node].iter().cloned());
+        }
+    }
+    live
+}
+
+/// Test harness: whether a and b agree on every string up to max_len bytes long,
+/// only bytes one of them uses are tried since every other byte fails both
+#[cfg(test)]
+pub(crate) fn languages_agree_up_to(a: &NFA, b: &NFA, max_len: usize) -> bool {
+    let alphabet: Vec<u8> = used_bytes(a).union(&used_bytes(b)).iter().collect();
+    agree_from(
+        a,
+        b,
+        &alphabet,
+        &simulate(a, b""),
+        &simulate(b, b""),
+        max_len,
+    )
+}
+
+/// Walks every string depth first so shared prefixes are only simulated once
+#[cfg(test)]
+fn agree_from(
+    a: &NFA,
+    b: &NFA,
+    alphabet: &[u8],
+    a_states: &HashSet<usize>,
+    b_states: &HashSet<usize>,
+    remaining: usize,
+) -> bool {
+    if a_states.contains(&(a.len() - 1)) != b_states.contains(&(b.len() - 1)) {
+        return false;
+    }
+    if remaining == 0 || (a_states.is_empty() && b_states.is_empty()) {
+        return true;
+    }
+    alphabet.iter().all(|byte| {
+        let a_next = step(a, a_states, *byte);
+        let b_next = step(b, b_states, *byte);
+        agree_from(a, b, alphabet, &a_next, &b_next, remaining - 1)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::regex::get_nfa;
+    use crate::regex::nfa::rast_to_nfa;
+    use crate::regex::parse::canonicalize;
+    use crate::Error;
+
+    #[test]
+    fn basic() -> Result<(), Error> {
+        let nfa = get_nfa("a(b|c)*")?;
+        assert!(matches(&nfa, b"a"));
+        assert!(matches(&nfa, b"abcb"));
+        assert!(!matches(&nfa, b""));
+        assert!(!matches(&nfa, b"ad"));
+        assert!(!matches(&nfa, b"ba"));
+
+        let nfa = get_nfa("a{2,3}")?;
+        assert!(!matches(&nfa, b"a"));
+        assert!(matches(&nfa, b"aa"));
+        assert!(matches(&nfa, b"aaa"));
+        assert!(!matches(&nfa, b"aaaa"));
+        Ok(())
+    }
+
+    #[test]
+    fn tracing() -> Result<(), Error> {
+        let nfa = get_nfa("ab")?;
+        let steps = trace(&nfa, b"ab");
+        let expected: Vec<HashSet<usize>> = vec![
+            [1, 2].iter().cloned().collect(),
+            [3].iter().cloned().collect(),
+        ];
+        assert_eq!(steps, expected);
+
+        let steps = trace(&nfa, b"bb");
+        assert!(steps.iter().all(|states| states.is_empty()));
+        assert_eq!(steps.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn sampling() -> Result<(), Error> {
+        assert_eq!(sample_strings(&get_nfa("a(b|c)")?, 10), [b"ab", b"ac"]);
+        assert_eq!(
+            sample_strings(&get_nfa("a*")?, 3),
+            [b"".to_vec(), b"a".to_vec(), b"aa".to_vec()]
+        );
+        assert_eq!(
+            sample_strings(&get_nfa("(b|a)c?")?, 3),
+            [b"a".to_vec(), b"b".to_vec(), b"ac".to_vec()]
+        );
+        assert!(sample_strings(&get_nfa("abc")?, 0).is_empty());
+
+        // sets are bitmaps so bytes always come out in ascending order
+        let nfa = get_nfa("[cab][ba]")?;
+        let first = sample_strings(&nfa, 10);
+        assert_eq!(first, [b"aa", b"ab", b"ba", b"bb", b"ca", b"cb"]);
+        assert_eq!(first, sample_strings(&nfa, 10));
+
+        // loops that can never reach the finish end the search instead of hanging it
+        let dead: NFA = vec![Character(b'a', 0), Epsilon(vec![])];
+        assert!(sample_strings(&dead, 10).is_empty());
+        let dead_branch: NFA = vec![
+            Epsilon(vec![1, 2]),
+            Character(b'a', 1),
+            Character(b'b', 3),
+            Epsilon(vec![]),
+        ];
+        assert_eq!(sample_strings(&dead_branch, 10), [b"b"]);
+
+        // prefixes that end in the same states aren't all searched
+        let nfa = get_nfa("[a-z]{10}")?;
+        assert_eq!(sample_strings(&nfa, 2), [b"aaaaaaaaaa", b"aaaaaaaaab"]);
+        let nfa = get_nfa("(a|b)*c")?;
+        assert_eq!(
+            sample_strings(&nfa, 4),
+            [
+                b"c".to_vec(),
+                b"ac".to_vec(),
+                b"bc".to_vec(),
+                b"aac".to_vec()
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn agreeing() -> Result<(), Error> {
+        assert!(languages_agree_up_to(&get_nfa("a*")?, &get_nfa("a*")?, 6));
+        assert!(!languages_agree_up_to(&get_nfa("a*")?, &get_nfa("a+")?, 6));
+        assert!(!languages_agree_up_to(
+            &get_nfa("a{2,3}")?,
+            &get_nfa("a{2,4}")?,
+            6
+        ));
+
+        // pairs that are built differently but should match the same strings
+        let pairs = [
+            ("a**", "a*"),
+            ("a*+", "a*"),
+            ("a?*", "a*"),
+            ("a{2,3}", "aa|aaa"),
+            ("a{,2}", "a?a?"),
+            ("a{2,}", "aaa*"),
+            ("a{2}", "aa"),
+            ("[b-d]", "b|c|d"),
+            ("[^a]", "[\\000-`b-\\177]"),
+            ("(?i)ab", "(a|A)(b|B)"),
+            ("\\101", "A"),
+            ("(a|b)*c", "(a*b*)*c"),
+        ];
+        for (left, right) in pairs.iter() {
+            let left = get_nfa(left)?;
+            let right = get_nfa(right)?;
+            assert!(languages_agree_up_to(&left, &right, 4));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn canonical_agrees() -> Result<(), Error> {
+        let patterns = [
+            "(ab)c|(ab)c",
+            "(a|a){1}b*",
+            "((a|b)|a)(c{1}|d)",
+            "(a|b|a)+c?",
+        ];
+        for pattern in patterns.iter() {
+            let rast = crate::regex::get_rast(pattern)?;
+            let before = rast_to_nfa(&rast);
+            let after = rast_to_nfa(&canonicalize(rast));
+            assert!(languages_agree_up_to(&before, &after, 5));
+        }
+        Ok(())
+    }
+}